@@ -0,0 +1,179 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+mod bluer_backend;
+#[cfg(test)]
+pub mod simulated;
+
+pub use crate::bluetooth::bluer_backend::BluerBackend;
+use crate::config::{AlertLevel, Config};
+use async_trait::async_trait;
+use bluer::Uuid;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_stream::Stream;
+
+pub type EventStream<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
+
+pub enum AdapterEvent {
+    Added(String),
+    Removed(String),
+}
+
+pub enum DeviceEvent {
+    Added(bluer::Address),
+    Removed(bluer::Address),
+}
+
+/// Bluetooth stack. Everything the daemon does over Bluetooth goes through the
+/// traits in this module, so that it can run against a simulated stack in tests.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    async fn adapter_names(&self) -> Result<Vec<String>, bluer::Error>;
+
+    /// Adapters appearing and disappearing, e.g. by hot plugging.
+    async fn adapter_events(&self) -> Result<EventStream<AdapterEvent>, bluer::Error>;
+
+    fn adapter(&self, adapter_name: &str) -> Result<Arc<dyn Adapter>, bluer::Error>;
+}
+
+#[async_trait]
+pub trait Adapter: Send + Sync {
+    async fn address(&self) -> Result<bluer::Address, bluer::Error>;
+
+    async fn set_powered(&self, powered: bool) -> Result<(), bluer::Error>;
+
+    /// Starts LE discovery. Stream reports devices coming into and going out of range.
+    async fn discover_devices(&self) -> Result<EventStream<DeviceEvent>, bluer::Error>;
+
+    fn device(&self, address: bluer::Address) -> Result<Arc<dyn Device>, bluer::Error>;
+}
+
+/// Remote device, as seen by one adapter.
+#[async_trait]
+pub trait Device: Send + Sync {
+    fn address(&self) -> bluer::Address;
+
+    fn adapter_name(&self) -> &str;
+
+    async fn name(&self) -> Result<Option<String>, bluer::Error>;
+
+    /// None if the device is not in range.
+    async fn rssi(&self) -> Result<Option<i16>, bluer::Error>;
+
+    async fn uuids(&self) -> Result<HashSet<Uuid>, bluer::Error>;
+
+    async fn manufacturer_data(&self) -> Result<HashMap<u16, Vec<u8>>, bluer::Error>;
+
+    async fn is_connected(&self) -> Result<bool, bluer::Error>;
+
+    async fn connect(&self) -> Result<(), bluer::Error>;
+
+    async fn disconnect(&self) -> Result<(), bluer::Error>;
+
+    /// Property changes of the device, described for logging. Stream ends when the
+    /// device goes away.
+    async fn events(&self) -> Result<EventStream<String>, bluer::Error>;
+
+    /// Finds GATT characteristic of a connected device.
+    async fn characteristic(
+        &self,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+    ) -> Result<Option<Box<dyn Characteristic>>, bluer::Error>;
+
+    /// Lists GATT services of a connected device.
+    async fn services(&self) -> Result<Vec<ServiceInfo>, bluer::Error>;
+}
+
+#[async_trait]
+pub trait Characteristic: Send + Sync {
+    async fn read(&self) -> Result<Vec<u8>, bluer::Error>;
+
+    async fn write(&self, value: &[u8]) -> Result<(), bluer::Error>;
+
+    async fn can_notify(&self) -> Result<bool, bluer::Error>;
+
+    async fn notify(&self) -> Result<EventStream<Vec<u8>>, bluer::Error>;
+}
+
+/// GATT service and its characteristics, as listed by `Device::services`.
+pub struct ServiceInfo {
+    pub uuid: Uuid,
+    pub characteristics: Vec<CharacteristicInfo>,
+}
+
+pub struct CharacteristicInfo {
+    pub uuid: Uuid,
+    /// Supported operations, such as read, write and notify.
+    pub flags: Vec<&'static str>,
+}
+
+pub static BUTTON_SERVICE: Uuid = Uuid::from_u128(0x0000ffe0_0000_1000_8000_00805f9b34fb);
+pub static BUTTON_CHARACTERISTIC: Uuid = Uuid::from_u128(0x0000ffe1_0000_1000_8000_00805f9b34fb);
+
+pub static IMMEDIATE_ALERT_SERVICE: Uuid = Uuid::from_u128(0x00001802_0000_1000_8000_00805f9b34fb);
+pub static LINK_LOSS_SERVICE: Uuid = Uuid::from_u128(0x00001803_0000_1000_8000_00805f9b34fb);
+pub static ALERT_LEVEL_CHARACTERISTIC: Uuid =
+    Uuid::from_u128(0x00002a06_0000_1000_8000_00805f9b34fb);
+
+pub static BATTERY_SERVICE: Uuid = Uuid::from_u128(0x0000180f_0000_1000_8000_00805f9b34fb);
+pub static BATTERY_LEVEL_CHARACTERISTIC: Uuid =
+    Uuid::from_u128(0x00002a19_0000_1000_8000_00805f9b34fb);
+
+/// Powers the adapter on and starts discovery on it, unless the config leaves the
+/// adapter alone. Failures are logged, and the adapter is then skipped.
+pub async fn start_discovery(
+    config: &Config,
+    backend: &dyn Backend,
+    adapter_name: &str,
+) -> Option<(Arc<dyn Adapter>, EventStream<DeviceEvent>)> {
+    if !config.is_adapter_allowed(adapter_name) {
+        return None;
+    }
+
+    let adapter = match backend.adapter(adapter_name) {
+        Ok(adapter) => adapter,
+        Err(err) => {
+            warn!(adapter_name:% = adapter_name; "Cannot get bluetooth adapter: {0}", err);
+            return None;
+        }
+    };
+
+    if let Err(err) = adapter.set_powered(true).await {
+        warn!(adapter_name:% = adapter_name; "Cannot set bluetooth adapter powered: {0}", err);
+    }
+
+    let stream = match adapter.discover_devices().await {
+        Ok(stream) => stream,
+        Err(err) => {
+            warn!(
+                adapter_name:% = adapter_name;
+                "Cannot get bluetooth adapter event stream: {0}", err
+            );
+            return None;
+        }
+    };
+    return Some((adapter, stream));
+}
+
+/// Writes alert level to the Alert Level characteristic of the given alert service.
+/// Both Immediate Alert and Link Loss services use the same characteristic.
+pub async fn write_alert_level(
+    device: &dyn Device,
+    service: Uuid,
+    level: AlertLevel,
+) -> Result<(), bluer::Error> {
+    let char = device
+        .characteristic(service, ALERT_LEVEL_CHARACTERISTIC)
+        .await?;
+    if let Some(char) = char {
+        return char.write(&[level as u8]).await;
+    }
+    Err(bluer::Error {
+        kind: bluer::ErrorKind::DoesNotExist,
+        message: String::from("No alert level characteristic"),
+    })
+}
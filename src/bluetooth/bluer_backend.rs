@@ -0,0 +1,221 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::bluetooth::{
+    Adapter, AdapterEvent, Backend, Characteristic, CharacteristicInfo, Device, DeviceEvent,
+    EventStream, ServiceInfo,
+};
+use async_trait::async_trait;
+use bluer::Uuid;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio_stream::StreamExt;
+
+/// Backend on BlueZ.
+pub struct BluerBackend {
+    session: bluer::Session,
+}
+
+impl BluerBackend {
+    pub fn new(session: bluer::Session) -> BluerBackend {
+        return BluerBackend { session: session };
+    }
+}
+
+#[async_trait]
+impl Backend for BluerBackend {
+    async fn adapter_names(&self) -> Result<Vec<String>, bluer::Error> {
+        return self.session.adapter_names().await;
+    }
+
+    async fn adapter_events(&self) -> Result<EventStream<AdapterEvent>, bluer::Error> {
+        let stream = self.session.events().await?;
+        return Ok(Box::pin(stream.map(|event| match event {
+            bluer::SessionEvent::AdapterAdded(adapter_name) => AdapterEvent::Added(adapter_name),
+            bluer::SessionEvent::AdapterRemoved(adapter_name) => {
+                AdapterEvent::Removed(adapter_name)
+            }
+        })));
+    }
+
+    fn adapter(&self, adapter_name: &str) -> Result<Arc<dyn Adapter>, bluer::Error> {
+        let adapter = self.session.adapter(adapter_name)?;
+        return Ok(Arc::new(BluerAdapter { adapter: adapter }));
+    }
+}
+
+struct BluerAdapter {
+    adapter: bluer::Adapter,
+}
+
+#[async_trait]
+impl Adapter for BluerAdapter {
+    async fn address(&self) -> Result<bluer::Address, bluer::Error> {
+        return self.adapter.address().await;
+    }
+
+    async fn set_powered(&self, powered: bool) -> Result<(), bluer::Error> {
+        return self.adapter.set_powered(powered).await;
+    }
+
+    async fn discover_devices(&self) -> Result<EventStream<DeviceEvent>, bluer::Error> {
+        // Poll only LE devices
+        let filter = bluer::DiscoveryFilter {
+            transport: bluer::DiscoveryTransport::Le,
+            ..Default::default()
+        };
+
+        match self.adapter.set_discovery_filter(filter).await {
+            Ok(()) => {}
+            Err(err) => {
+                warn!(
+                    adapter_name:% = self.adapter.name();
+                    "Couldn't set LE-only discovery filter: {}", err
+                );
+            }
+        };
+
+        let stream = self.adapter.discover_devices_with_changes().await?;
+        return Ok(Box::pin(stream.filter_map(|event| match event {
+            bluer::AdapterEvent::DeviceAdded(device_address) => {
+                Some(DeviceEvent::Added(device_address))
+            }
+            bluer::AdapterEvent::DeviceRemoved(device_address) => {
+                Some(DeviceEvent::Removed(device_address))
+            }
+            bluer::AdapterEvent::PropertyChanged(_property_change) => None,
+        })));
+    }
+
+    fn device(&self, address: bluer::Address) -> Result<Arc<dyn Device>, bluer::Error> {
+        let device = self.adapter.device(address)?;
+        return Ok(Arc::new(BluerDevice { device: device }));
+    }
+}
+
+struct BluerDevice {
+    device: bluer::Device,
+}
+
+#[async_trait]
+impl Device for BluerDevice {
+    fn address(&self) -> bluer::Address {
+        return self.device.address();
+    }
+
+    fn adapter_name(&self) -> &str {
+        return self.device.adapter_name();
+    }
+
+    async fn name(&self) -> Result<Option<String>, bluer::Error> {
+        return self.device.name().await;
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>, bluer::Error> {
+        return self.device.rssi().await;
+    }
+
+    async fn uuids(&self) -> Result<HashSet<Uuid>, bluer::Error> {
+        return Ok(self.device.uuids().await?.unwrap_or_default());
+    }
+
+    async fn manufacturer_data(&self) -> Result<HashMap<u16, Vec<u8>>, bluer::Error> {
+        return Ok(self.device.manufacturer_data().await?.unwrap_or_default());
+    }
+
+    async fn is_connected(&self) -> Result<bool, bluer::Error> {
+        return self.device.is_connected().await;
+    }
+
+    async fn connect(&self) -> Result<(), bluer::Error> {
+        return self.device.connect().await;
+    }
+
+    async fn disconnect(&self) -> Result<(), bluer::Error> {
+        return self.device.disconnect().await;
+    }
+
+    async fn events(&self) -> Result<EventStream<String>, bluer::Error> {
+        let stream = self.device.events().await?;
+        return Ok(Box::pin(stream.map(|event| format!("{:?}", event))));
+    }
+
+    async fn characteristic(
+        &self,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+    ) -> Result<Option<Box<dyn Characteristic>>, bluer::Error> {
+        for service in self.device.services().await? {
+            let uuid = service.uuid().await?;
+            if uuid != service_uuid {
+                continue;
+            }
+
+            for char in service.characteristics().await? {
+                let uuid = char.uuid().await?;
+                if uuid != characteristic_uuid {
+                    continue;
+                }
+
+                return Ok(Some(Box::new(BluerCharacteristic { char: char })));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn services(&self) -> Result<Vec<ServiceInfo>, bluer::Error> {
+        let mut services: Vec<ServiceInfo> = Vec::new();
+        for service in self.device.services().await? {
+            let mut characteristics: Vec<CharacteristicInfo> = Vec::new();
+            for char in service.characteristics().await? {
+                let flags = char.flags().await?;
+                let flags = [
+                    ("broadcast", flags.broadcast),
+                    ("read", flags.read),
+                    ("write-without-response", flags.write_without_response),
+                    ("write", flags.write),
+                    ("notify", flags.notify),
+                    ("indicate", flags.indicate),
+                ];
+                characteristics.push(CharacteristicInfo {
+                    uuid: char.uuid().await?,
+                    flags: flags
+                        .iter()
+                        .filter(|(_, is_set)| *is_set)
+                        .map(|(flag, _)| *flag)
+                        .collect(),
+                });
+            }
+            services.push(ServiceInfo {
+                uuid: service.uuid().await?,
+                characteristics: characteristics,
+            });
+        }
+        return Ok(services);
+    }
+}
+
+struct BluerCharacteristic {
+    char: bluer::gatt::remote::Characteristic,
+}
+
+#[async_trait]
+impl Characteristic for BluerCharacteristic {
+    async fn read(&self) -> Result<Vec<u8>, bluer::Error> {
+        return self.char.read().await;
+    }
+
+    async fn write(&self, value: &[u8]) -> Result<(), bluer::Error> {
+        return self.char.write(value).await;
+    }
+
+    async fn can_notify(&self) -> Result<bool, bluer::Error> {
+        return Ok(self.char.flags().await?.notify);
+    }
+
+    async fn notify(&self) -> Result<EventStream<Vec<u8>>, bluer::Error> {
+        let stream = self.char.notify().await?;
+        return Ok(Box::pin(stream));
+    }
+}
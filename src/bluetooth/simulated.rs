@@ -0,0 +1,613 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::bluetooth::{
+    Adapter, AdapterEvent, Backend, Characteristic, CharacteristicInfo, Device, DeviceEvent,
+    EventStream, ServiceInfo, ALERT_LEVEL_CHARACTERISTIC, BATTERY_LEVEL_CHARACTERISTIC,
+    BATTERY_SERVICE, BUTTON_CHARACTERISTIC, BUTTON_SERVICE, IMMEDIATE_ALERT_SERVICE,
+    LINK_LOSS_SERVICE,
+};
+use async_trait::async_trait;
+use bluer::Uuid;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// In-memory Bluetooth stack for tests. The test scripts adapters and tags through
+/// the methods of this struct, and the daemon sees the changes through `Backend`.
+#[derive(Clone)]
+pub struct SimulatedBackend {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    adapters: HashMap<String, SimulatedAdapterState>,
+    adapter_listeners: Vec<mpsc::UnboundedSender<AdapterEvent>>,
+    tags: HashMap<bluer::Address, TagState>,
+}
+
+struct SimulatedAdapterState {
+    address: bluer::Address,
+    powered: bool,
+    /// Tags in range, with their RSSI.
+    in_range: HashMap<bluer::Address, i16>,
+    listeners: Vec<mpsc::UnboundedSender<DeviceEvent>>,
+}
+
+#[derive(Default)]
+struct TagState {
+    name: Option<String>,
+    uuids: HashSet<Uuid>,
+    manufacturer_data: HashMap<u16, Vec<u8>>,
+    has_link_loss: bool,
+    battery_level: Option<u8>,
+    battery_notifies: bool,
+    /// Adapter the tag is connected to.
+    connected_on: Option<String>,
+    failing_connects: u32,
+    failing_disconnects: u32,
+    connect_count: u32,
+    immediate_alert_level: Option<u8>,
+    link_loss_level: Option<u8>,
+    connection_listeners: Vec<mpsc::UnboundedSender<String>>,
+    button_listeners: Vec<mpsc::UnboundedSender<Vec<u8>>>,
+    battery_listeners: Vec<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+impl SimulatedBackend {
+    pub fn new() -> SimulatedBackend {
+        return SimulatedBackend {
+            state: Arc::new(Mutex::new(State::default())),
+        };
+    }
+
+    pub fn add_adapter(&self, adapter_name: &str, address: bluer::Address) {
+        let mut state = self.state.lock().unwrap();
+        state.adapters.insert(
+            adapter_name.to_string(),
+            SimulatedAdapterState {
+                address: address,
+                powered: false,
+                in_range: HashMap::new(),
+                listeners: Vec::new(),
+            },
+        );
+        let adapter_name = adapter_name.to_string();
+        state.adapter_listeners.retain(|listener| {
+            listener
+                .send(AdapterEvent::Added(adapter_name.clone()))
+                .is_ok()
+        });
+    }
+
+    /// Unplugs the adapter. Tags connected through it are disconnected, but their
+    /// streams are left open, like BlueZ does until the link supervision timeout.
+    pub fn remove_adapter(&self, adapter_name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.adapters.remove(adapter_name);
+        for tag in state.tags.values_mut() {
+            if tag.connected_on.as_deref() == Some(adapter_name) {
+                tag.connected_on = None;
+            }
+        }
+        let adapter_name = adapter_name.to_string();
+        state.adapter_listeners.retain(|listener| {
+            listener
+                .send(AdapterEvent::Removed(adapter_name.clone()))
+                .is_ok()
+        });
+    }
+
+    pub fn is_powered(&self, adapter_name: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        return match state.adapters.get(adapter_name) {
+            Some(adapter) => adapter.powered,
+            None => false,
+        };
+    }
+
+    /// Defines a tag with the Immediate Alert and button services.
+    pub fn add_tag(&self, address: bluer::Address, name: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        state.tags.insert(
+            address,
+            TagState {
+                name: name.map(|name| name.to_string()),
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn set_advertisement(
+        &self,
+        address: bluer::Address,
+        uuids: HashSet<Uuid>,
+        manufacturer_data: HashMap<u16, Vec<u8>>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let tag = state.tags.get_mut(&address).unwrap();
+        tag.uuids = uuids;
+        tag.manufacturer_data = manufacturer_data;
+    }
+
+    pub fn set_link_loss_supported(&self, address: bluer::Address, is_supported: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.tags.get_mut(&address).unwrap().has_link_loss = is_supported;
+    }
+
+    /// Battery service is present when the level is set. Notifies the new level if
+    /// the tag supports it.
+    pub fn set_battery_level(&self, address: bluer::Address, level: Option<u8>) {
+        let mut state = self.state.lock().unwrap();
+        let tag = state.tags.get_mut(&address).unwrap();
+        tag.battery_level = level;
+        if let Some(level) = level {
+            tag.battery_listeners
+                .retain(|listener| listener.send(vec![level]).is_ok());
+        }
+    }
+
+    /// Without notifications, which is the default, the battery level must be polled.
+    pub fn set_battery_notifies(&self, address: bluer::Address, notifies: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.tags.get_mut(&address).unwrap().battery_notifies = notifies;
+    }
+
+    /// Moves the tag into range of the adapter, or updates its RSSI if it already is.
+    pub fn set_in_range(&self, adapter_name: &str, address: bluer::Address, rssi: i16) {
+        let mut state = self.state.lock().unwrap();
+        let adapter = state.adapters.get_mut(adapter_name).unwrap();
+        if adapter.in_range.insert(address, rssi).is_none() {
+            adapter
+                .listeners
+                .retain(|listener| listener.send(DeviceEvent::Added(address)).is_ok());
+        }
+    }
+
+    /// Moves the tag out of range of the adapter, dropping the connection through it.
+    pub fn set_out_of_range(&self, adapter_name: &str, address: bluer::Address) {
+        let mut state = self.state.lock().unwrap();
+        let adapter = state.adapters.get_mut(adapter_name).unwrap();
+        if adapter.in_range.remove(&address).is_some() {
+            adapter
+                .listeners
+                .retain(|listener| listener.send(DeviceEvent::Removed(address)).is_ok());
+        }
+        if let Some(tag) = state.tags.get_mut(&address) {
+            if tag.connected_on.as_deref() == Some(adapter_name) {
+                disconnect_tag(tag);
+            }
+        }
+    }
+
+    /// Makes the next `count` connection attempts to the tag fail.
+    pub fn fail_connects(&self, address: bluer::Address, count: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.tags.get_mut(&address).unwrap().failing_connects = count;
+    }
+
+    /// Makes the next `count` disconnection attempts of the tag fail.
+    pub fn fail_disconnects(&self, address: bluer::Address, count: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.tags.get_mut(&address).unwrap().failing_disconnects = count;
+    }
+
+    /// Drops the connection, as if the tag lost power.
+    pub fn drop_connection(&self, address: bluer::Address) {
+        let mut state = self.state.lock().unwrap();
+        disconnect_tag(state.tags.get_mut(&address).unwrap());
+    }
+
+    /// Sends a button notification. Returns false if no one was listening.
+    pub fn press_button(&self, address: bluer::Address) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let tag = state.tags.get_mut(&address).unwrap();
+        tag.button_listeners
+            .retain(|listener| listener.send(vec![1]).is_ok());
+        return !tag.button_listeners.is_empty();
+    }
+
+    /// Adapter the tag is connected to.
+    pub fn connected_on(&self, address: bluer::Address) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        return state.tags.get(&address)?.connected_on.clone();
+    }
+
+    /// Number of successful connections to the tag.
+    pub fn connect_count(&self, address: bluer::Address) -> u32 {
+        let state = self.state.lock().unwrap();
+        return state.tags.get(&address).unwrap().connect_count;
+    }
+
+    /// Latest value written to the Immediate Alert service.
+    pub fn immediate_alert_level(&self, address: bluer::Address) -> Option<u8> {
+        let state = self.state.lock().unwrap();
+        return state.tags.get(&address)?.immediate_alert_level;
+    }
+
+    /// Latest value written to the Link Loss service.
+    pub fn link_loss_level(&self, address: bluer::Address) -> Option<u8> {
+        let state = self.state.lock().unwrap();
+        return state.tags.get(&address)?.link_loss_level;
+    }
+}
+
+fn disconnect_tag(tag: &mut TagState) {
+    tag.connected_on = None;
+    // Dropping the senders ends the streams
+    tag.connection_listeners.clear();
+    tag.button_listeners.clear();
+    tag.battery_listeners.clear();
+}
+
+fn error(kind: bluer::ErrorKind, message: &str) -> bluer::Error {
+    return bluer::Error {
+        kind: kind,
+        message: message.to_string(),
+    };
+}
+
+#[async_trait]
+impl Backend for SimulatedBackend {
+    async fn adapter_names(&self) -> Result<Vec<String>, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        return Ok(state.adapters.keys().cloned().collect());
+    }
+
+    async fn adapter_events(&self) -> Result<EventStream<AdapterEvent>, bluer::Error> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().adapter_listeners.push(sender);
+        return Ok(Box::pin(UnboundedReceiverStream::new(receiver)));
+    }
+
+    fn adapter(&self, adapter_name: &str) -> Result<Arc<dyn Adapter>, bluer::Error> {
+        if !self
+            .state
+            .lock()
+            .unwrap()
+            .adapters
+            .contains_key(adapter_name)
+        {
+            return Err(error(bluer::ErrorKind::NotFound, "No such adapter"));
+        }
+        return Ok(Arc::new(SimulatedAdapter {
+            state: self.state.clone(),
+            adapter_name: adapter_name.to_string(),
+        }));
+    }
+}
+
+struct SimulatedAdapter {
+    state: Arc<Mutex<State>>,
+    adapter_name: String,
+}
+
+#[async_trait]
+impl Adapter for SimulatedAdapter {
+    async fn address(&self) -> Result<bluer::Address, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        return match state.adapters.get(&self.adapter_name) {
+            Some(adapter) => Ok(adapter.address),
+            None => Err(error(bluer::ErrorKind::NotFound, "Adapter removed")),
+        };
+    }
+
+    async fn set_powered(&self, powered: bool) -> Result<(), bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        return match state.adapters.get_mut(&self.adapter_name) {
+            Some(adapter) => {
+                adapter.powered = powered;
+                Ok(())
+            }
+            None => Err(error(bluer::ErrorKind::NotFound, "Adapter removed")),
+        };
+    }
+
+    async fn discover_devices(&self) -> Result<EventStream<DeviceEvent>, bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        let adapter = match state.adapters.get_mut(&self.adapter_name) {
+            Some(adapter) => adapter,
+            None => return Err(error(bluer::ErrorKind::NotFound, "Adapter removed")),
+        };
+
+        // Devices already in range are reported first, like BlueZ does
+        let (sender, receiver) = mpsc::unbounded_channel();
+        for address in adapter.in_range.keys() {
+            let _ = sender.send(DeviceEvent::Added(*address));
+        }
+        adapter.listeners.push(sender);
+        return Ok(Box::pin(UnboundedReceiverStream::new(receiver)));
+    }
+
+    fn device(&self, address: bluer::Address) -> Result<Arc<dyn Device>, bluer::Error> {
+        return Ok(Arc::new(SimulatedDevice {
+            state: self.state.clone(),
+            adapter_name: self.adapter_name.clone(),
+            address: address,
+        }));
+    }
+}
+
+struct SimulatedDevice {
+    state: Arc<Mutex<State>>,
+    adapter_name: String,
+    address: bluer::Address,
+}
+
+impl SimulatedDevice {
+    fn is_in_range(&self, state: &State) -> bool {
+        return match state.adapters.get(&self.adapter_name) {
+            Some(adapter) => adapter.in_range.contains_key(&self.address),
+            None => false,
+        };
+    }
+
+    fn is_connected_here(&self, state: &State) -> bool {
+        return match state.tags.get(&self.address) {
+            Some(tag) => tag.connected_on.as_deref() == Some(self.adapter_name.as_str()),
+            None => false,
+        };
+    }
+}
+
+#[async_trait]
+impl Device for SimulatedDevice {
+    fn address(&self) -> bluer::Address {
+        return self.address;
+    }
+
+    fn adapter_name(&self) -> &str {
+        return &self.adapter_name;
+    }
+
+    async fn name(&self) -> Result<Option<String>, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        if !self.is_in_range(&state) {
+            return Err(error(bluer::ErrorKind::DoesNotExist, "Device is gone"));
+        }
+        return Ok(match state.tags.get(&self.address) {
+            Some(tag) => tag.name.clone(),
+            None => None,
+        });
+    }
+
+    async fn rssi(&self) -> Result<Option<i16>, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        return match state.adapters.get(&self.adapter_name) {
+            Some(adapter) => Ok(adapter.in_range.get(&self.address).copied()),
+            None => Err(error(bluer::ErrorKind::DoesNotExist, "Adapter removed")),
+        };
+    }
+
+    async fn uuids(&self) -> Result<HashSet<Uuid>, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        return Ok(match state.tags.get(&self.address) {
+            Some(tag) => tag.uuids.clone(),
+            None => HashSet::new(),
+        });
+    }
+
+    async fn manufacturer_data(&self) -> Result<HashMap<u16, Vec<u8>>, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        return Ok(match state.tags.get(&self.address) {
+            Some(tag) => tag.manufacturer_data.clone(),
+            None => HashMap::new(),
+        });
+    }
+
+    async fn is_connected(&self) -> Result<bool, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        return Ok(self.is_connected_here(&state));
+    }
+
+    async fn connect(&self) -> Result<(), bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        if !self.is_in_range(&state) {
+            return Err(error(bluer::ErrorKind::DoesNotExist, "Device is gone"));
+        }
+        let tag = match state.tags.get_mut(&self.address) {
+            Some(tag) => tag,
+            None => return Err(error(bluer::ErrorKind::NotSupported, "Not a tag")),
+        };
+        if tag.failing_connects > 0 {
+            tag.failing_connects -= 1;
+            return Err(error(
+                bluer::ErrorKind::ConnectionAttemptFailed,
+                "Simulated connection failure",
+            ));
+        }
+        if tag.connected_on.is_some() {
+            return Err(error(
+                bluer::ErrorKind::AlreadyConnected,
+                "Connected elsewhere",
+            ));
+        }
+        tag.connected_on = Some(self.adapter_name.clone());
+        tag.connect_count += 1;
+        return Ok(());
+    }
+
+    async fn disconnect(&self) -> Result<(), bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        if !self.is_connected_here(&state) {
+            return Ok(());
+        }
+        let tag = state.tags.get_mut(&self.address).unwrap();
+        if tag.failing_disconnects > 0 {
+            tag.failing_disconnects -= 1;
+            return Err(error(
+                bluer::ErrorKind::Failed,
+                "Simulated disconnection failure",
+            ));
+        }
+        disconnect_tag(tag);
+        return Ok(());
+    }
+
+    async fn events(&self) -> Result<EventStream<String>, bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        if self.is_connected_here(&state) {
+            let tag = state.tags.get_mut(&self.address).unwrap();
+            tag.connection_listeners.push(sender);
+        }
+        // Otherwise the sender is dropped and the stream ends immediately
+        return Ok(Box::pin(UnboundedReceiverStream::new(receiver)));
+    }
+
+    async fn characteristic(
+        &self,
+        service_uuid: Uuid,
+        characteristic_uuid: Uuid,
+    ) -> Result<Option<Box<dyn Characteristic>>, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        if !self.is_connected_here(&state) {
+            return Err(error(bluer::ErrorKind::NotReady, "Not connected"));
+        }
+        let tag = state.tags.get(&self.address).unwrap();
+
+        let kind = if service_uuid == BUTTON_SERVICE && characteristic_uuid == BUTTON_CHARACTERISTIC
+        {
+            CharacteristicKind::Button
+        } else if service_uuid == IMMEDIATE_ALERT_SERVICE
+            && characteristic_uuid == ALERT_LEVEL_CHARACTERISTIC
+        {
+            CharacteristicKind::ImmediateAlert
+        } else if service_uuid == LINK_LOSS_SERVICE
+            && characteristic_uuid == ALERT_LEVEL_CHARACTERISTIC
+            && tag.has_link_loss
+        {
+            CharacteristicKind::LinkLoss
+        } else if service_uuid == BATTERY_SERVICE
+            && characteristic_uuid == BATTERY_LEVEL_CHARACTERISTIC
+            && tag.battery_level.is_some()
+        {
+            CharacteristicKind::Battery
+        } else {
+            return Ok(None);
+        };
+
+        return Ok(Some(Box::new(SimulatedCharacteristic {
+            state: self.state.clone(),
+            address: self.address,
+            kind: kind,
+        })));
+    }
+
+    async fn services(&self) -> Result<Vec<ServiceInfo>, bluer::Error> {
+        let state = self.state.lock().unwrap();
+        if !self.is_connected_here(&state) {
+            return Err(error(bluer::ErrorKind::NotReady, "Not connected"));
+        }
+        let tag = state.tags.get(&self.address).unwrap();
+
+        let service = |uuid: Uuid, characteristic: Uuid, flags: &[&'static str]| ServiceInfo {
+            uuid: uuid,
+            characteristics: vec![CharacteristicInfo {
+                uuid: characteristic,
+                flags: flags.to_vec(),
+            }],
+        };
+        let mut services = vec![
+            service(BUTTON_SERVICE, BUTTON_CHARACTERISTIC, &["notify"]),
+            service(
+                IMMEDIATE_ALERT_SERVICE,
+                ALERT_LEVEL_CHARACTERISTIC,
+                &["write"],
+            ),
+        ];
+        if tag.has_link_loss {
+            services.push(service(
+                LINK_LOSS_SERVICE,
+                ALERT_LEVEL_CHARACTERISTIC,
+                &["read", "write"],
+            ));
+        }
+        if tag.battery_level.is_some() {
+            services.push(service(
+                BATTERY_SERVICE,
+                BATTERY_LEVEL_CHARACTERISTIC,
+                if tag.battery_notifies {
+                    &["read", "notify"]
+                } else {
+                    &["read"]
+                },
+            ));
+        }
+        return Ok(services);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CharacteristicKind {
+    Button,
+    ImmediateAlert,
+    LinkLoss,
+    Battery,
+}
+
+struct SimulatedCharacteristic {
+    state: Arc<Mutex<State>>,
+    address: bluer::Address,
+    kind: CharacteristicKind,
+}
+
+impl SimulatedCharacteristic {
+    fn tag<'a>(&self, state: &'a mut State) -> Result<&'a mut TagState, bluer::Error> {
+        return match state.tags.get_mut(&self.address) {
+            Some(tag) if tag.connected_on.is_some() => Ok(tag),
+            _ => Err(error(bluer::ErrorKind::NotReady, "Not connected")),
+        };
+    }
+}
+
+#[async_trait]
+impl Characteristic for SimulatedCharacteristic {
+    async fn read(&self) -> Result<Vec<u8>, bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        let tag = self.tag(&mut state)?;
+        return match self.kind {
+            CharacteristicKind::Battery => Ok(tag.battery_level.into_iter().collect()),
+            _ => Err(error(bluer::ErrorKind::NotPermitted, "Not readable")),
+        };
+    }
+
+    async fn write(&self, value: &[u8]) -> Result<(), bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        let tag = self.tag(&mut state)?;
+        match self.kind {
+            CharacteristicKind::ImmediateAlert => {
+                tag.immediate_alert_level = value.first().copied()
+            }
+            CharacteristicKind::LinkLoss => tag.link_loss_level = value.first().copied(),
+            _ => return Err(error(bluer::ErrorKind::NotPermitted, "Not writable")),
+        }
+        return Ok(());
+    }
+
+    async fn can_notify(&self) -> Result<bool, bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        let tag = self.tag(&mut state)?;
+        return Ok(match self.kind {
+            CharacteristicKind::Button => true,
+            CharacteristicKind::Battery => tag.battery_notifies,
+            _ => false,
+        });
+    }
+
+    async fn notify(&self) -> Result<EventStream<Vec<u8>>, bluer::Error> {
+        let mut state = self.state.lock().unwrap();
+        let tag = self.tag(&mut state)?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        match self.kind {
+            CharacteristicKind::Button => tag.button_listeners.push(sender),
+            CharacteristicKind::Battery if tag.battery_notifies => {
+                tag.battery_listeners.push(sender)
+            }
+            _ => return Err(error(bluer::ErrorKind::NotSupported, "Cannot notify")),
+        }
+        return Ok(Box::pin(UnboundedReceiverStream::new(receiver)));
+    }
+}
@@ -0,0 +1,94 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use std::time::Duration;
+
+pub const USAGE: &str = "Usage: itag2mqttd [run] <config.ini>
+       itag2mqttd check-config <config.ini>
+       itag2mqttd scan <config.ini> [seconds]
+       itag2mqttd probe <config.ini> <address>
+       itag2mqttd adopt <config.ini> <address>
+
+Commands:
+  run           Bridge tags to MQTT. The default when only a config file is given.
+  check-config  Validate the config, and print it with every default filled in.
+  scan          List nearby tags and their RSSI on every adapter, without connecting.
+                Scans for 10 seconds unless told otherwise.
+  probe         Connect to a tag, list its GATT services, beep it and read its battery.
+  adopt         Add a tag to the inventory, so that it is managed with only_known_devices.";
+
+/// How long `scan` runs when not given.
+const DEFAULT_SCAN_DURATION: Duration = Duration::from_secs(10);
+
+/// Subcommand and its arguments.
+pub enum Command {
+    Help,
+    Run {
+        config_file: String,
+    },
+    CheckConfig {
+        config_file: String,
+    },
+    Scan {
+        config_file: String,
+        duration: Duration,
+    },
+    Probe {
+        config_file: String,
+        address: bluer::Address,
+    },
+    Adopt {
+        config_file: String,
+        address: bluer::Address,
+    },
+}
+
+impl Command {
+    /// Parses the arguments, without the program name.
+    pub fn parse(args: &[String]) -> Result<Command, String> {
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        return match args.as_slice() {
+            [] => Err("Config file path was not given on command line".to_string()),
+            ["-h"] | ["--help"] | ["help"] => Ok(Command::Help),
+            ["run", config_file] => Ok(Command::Run {
+                config_file: config_file.to_string(),
+            }),
+            ["check-config", config_file] => Ok(Command::CheckConfig {
+                config_file: config_file.to_string(),
+            }),
+            ["scan", config_file] => Ok(Command::Scan {
+                config_file: config_file.to_string(),
+                duration: DEFAULT_SCAN_DURATION,
+            }),
+            ["scan", config_file, seconds] => match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Ok(Command::Scan {
+                    config_file: config_file.to_string(),
+                    duration: Duration::from_secs(seconds),
+                }),
+                _ => Err(format!("Invalid scan duration {0}", seconds)),
+            },
+            ["probe", config_file, address] => match address.parse::<bluer::Address>() {
+                Ok(address) => Ok(Command::Probe {
+                    config_file: config_file.to_string(),
+                    address: address,
+                }),
+                Err(_err) => Err(format!("Invalid device address {0}", address)),
+            },
+            ["adopt", config_file, address] => match address.parse::<bluer::Address>() {
+                Ok(address) => Ok(Command::Adopt {
+                    config_file: config_file.to_string(),
+                    address: address,
+                }),
+                Err(_err) => Err(format!("Invalid device address {0}", address)),
+            },
+            ["run" | "check-config" | "scan" | "probe" | "adopt", ..] => {
+                Err(format!("Wrong arguments for '{0}'", args[0]))
+            }
+            // Config file alone, as before there were commands
+            [config_file] if !config_file.starts_with('-') => Ok(Command::Run {
+                config_file: config_file.to_string(),
+            }),
+            _ => Err(format!("Unknown command '{0}'", args[0])),
+        };
+    }
+}
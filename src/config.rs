@@ -1,13 +1,168 @@
 // Author: Jarkko Pöyry
 // See LICENSE for License
 
+use crate::device_matcher::{
+    parse_uuid, AddressMatcher, DeviceMatcher, ManufacturerMatcher, NameMatcher, ServiceMatcher,
+};
+use crate::topic_template::TopicTemplate;
 use configparser::ini::Ini;
+use log::LevelFilter;
+use std::collections::{HashMap, HashSet};
 use std::format;
+use std::str::FromStr;
+use std::time::Duration;
 
 pub struct Config {
     pub mqtt_host: String,
     pub mqtt_port: u16,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    /// File the password was read from, if it was not given inline.
+    pub mqtt_password_file: Option<String>,
+    pub mqtt_tls: Option<MqttTls>,
+    pub mqtt_client_id: String,
+    pub mqtt_topics: MqttTopics,
+    pub mqtt_payload_format: PayloadFormat,
     pub bt_adapters: Vec<String>,
+    /// Ignore tags that are neither configured nor in the inventory.
+    pub only_known_devices: bool,
+    pub inventory_file: Option<String>,
+    /// Decides which discovered devices are tags.
+    pub device_matcher: DeviceMatcher,
+    /// Time given to disconnecting tags and flushing MQTT on shutdown.
+    pub shutdown_timeout: Duration,
+    pub ha_discovery_prefix: Option<String>,
+    pub logging: LoggingConfig,
+    pub device_defaults: DeviceConfig,
+    pub devices: HashMap<bluer::Address, DeviceConfig>,
+}
+
+/// PEM encoded TLS material, read and validated when config is read.
+pub struct MqttTls {
+    /// CA bundle. If missing, the platform certificates are used.
+    pub ca: Option<Vec<u8>>,
+    pub ca_file: Option<String>,
+    /// Client certificate chain and private key.
+    pub client_auth: Option<(Vec<u8>, Vec<u8>)>,
+    /// Files of the client certificate chain and private key.
+    pub client_auth_files: Option<(String, String)>,
+}
+
+/// Topics of every kind of message, from `topic_*` keys in [mqtt] block.
+#[derive(Clone)]
+pub struct MqttTopics {
+    /// Value of `topic_prefix`. Also sets apart the Home Assistant entities of daemons
+    /// that share a broker.
+    pub prefix: String,
+    pub bridge_state: TopicTemplate,
+    pub presence: TopicTemplate,
+    pub button_click: TopicTemplate,
+    pub button_action: TopicTemplate,
+    pub battery: TopicTemplate,
+    pub rssi: TopicTemplate,
+    pub adapter_rssi: TopicTemplate,
+    pub capabilities: TopicTemplate,
+    pub event: TopicTemplate,
+    pub diagnostics: TopicTemplate,
+    pub alert_set: TopicTemplate,
+    pub alert_result: TopicTemplate,
+}
+
+/// Format of device message payloads.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PayloadFormat {
+    /// Bare value, such as `1` or `87`.
+    Raw,
+    /// JSON object with the value and metadata of the message.
+    Json,
+}
+
+/// Settings of [logging] block.
+#[derive(Clone)]
+pub struct LoggingConfig {
+    /// Level of modules that have no filter.
+    pub level: LevelFilter,
+    /// Levels of modules and their submodules, such as `rumqttc=warn`.
+    pub filters: Vec<(String, LevelFilter)>,
+    pub format: LogFormat,
+    /// Start lines with the time. Off when journald adds it anyway.
+    pub timestamps: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable line with `key=value` fields at the end.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Settings of a single device. Defaults come from [bluetooth] block and can be
+/// overridden in a [device AA:BB:CC:DD:EE:FF] block. Name, alias, enabled and
+/// log_level can only be set in the device block.
+#[derive(Clone)]
+pub struct DeviceConfig {
+    /// Friendly name, shown in Home Assistant.
+    pub name: Option<String>,
+    /// Replaces the address in topics with `{alias}` placeholder.
+    pub alias: Option<String>,
+    /// Disabled devices are ignored.
+    pub enabled: bool,
+    pub battery_poll_interval: Duration,
+    pub battery_low_threshold: u8,
+    pub rssi_interval: Duration,
+    pub click_window: Duration,
+    pub link_loss_alert: AlertLevel,
+    /// Alert commands without a duration are turned off after this.
+    pub alert_duration: Option<Duration>,
+    pub connect_timeout: Duration,
+    /// Reconnect delay doubles from min to max on consecutive failures.
+    pub reconnect_min_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    /// Random variation of the reconnect delay, as a fraction of it.
+    pub reconnect_jitter: f64,
+    /// Connection that stays up this long resets the reconnect delay.
+    pub reconnect_reset_after: Duration,
+    /// Connection moves to another adapter that sees the tag this many dB stronger,
+    /// for at least the dwell time. None disables handover.
+    pub handover_margin: Option<i16>,
+    pub handover_dwell: Duration,
+    /// Tag that has not been seen for this long is forgotten until it comes back.
+    /// None keeps it forever.
+    pub expire_after: Option<Duration>,
+    /// Clear retained topics of a forgotten tag. Its discovery configs are always removed.
+    pub expire_clear_retained: bool,
+    /// Turn alerts off before disconnecting on shutdown, so that the tag does not beep.
+    pub silence_on_shutdown: bool,
+    /// Level of log records about this device, instead of the level of their module.
+    pub log_level: Option<LevelFilter>,
+}
+
+/// Alert Level characteristic values.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AlertLevel {
+    Off = 0,
+    Mild = 1,
+    High = 2,
+}
+
+impl AlertLevel {
+    pub fn parse(value: &str) -> Option<AlertLevel> {
+        return match value.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(AlertLevel::Off),
+            "mild" => Some(AlertLevel::Mild),
+            "high" => Some(AlertLevel::High),
+            _ => None,
+        };
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            AlertLevel::Off => "off",
+            AlertLevel::Mild => "mild",
+            AlertLevel::High => "high",
+        };
+    }
 }
 
 impl Config {
@@ -28,10 +183,144 @@ impl Config {
         let mqtt_port = config
             .getint("mqtt", "port")?
             .ok_or("Missing 'port' in [mqtt] block")?;
+        let mqtt_username = config.get("mqtt", "username");
+        let mqtt_password_file = config.get("mqtt", "password_file");
+        let mqtt_password = match (config.get("mqtt", "password"), &mqtt_password_file) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    "Only one of 'password' and 'password_file' is allowed in [mqtt] block"
+                        .to_string(),
+                )
+            }
+            (Some(password), None) => Some(password),
+            (None, Some(password_file)) => match std::fs::read_to_string(password_file) {
+                Ok(password) => Some(password.trim_end_matches(&['\r', '\n']).to_string()),
+                Err(err) => {
+                    return Err(format!(
+                        "Cannot read 'password_file' {0} in [mqtt] block: {1}",
+                        password_file, err
+                    ))
+                }
+            },
+            (None, None) => None,
+        };
+        if mqtt_password.is_some() && mqtt_username.is_none() {
+            return Err("Missing 'username' in [mqtt] block, required with password".to_string());
+        }
+        let mqtt_tls = if config.getbool("mqtt", "tls")?.unwrap_or(false) {
+            Some(Config::parse_mqtt_tls(&config)?)
+        } else {
+            None
+        };
+        let mqtt_client_id = config
+            .get("mqtt", "client_id")
+            .unwrap_or("itag2mqttd".to_string());
+        let mqtt_topics = Config::parse_mqtt_topics(&config)?;
+        let mqtt_payload_format = match config.get("mqtt", "payload_format") {
+            Some(payload_format) => match payload_format.trim().to_ascii_lowercase().as_str() {
+                "raw" => PayloadFormat::Raw,
+                "json" => PayloadFormat::Json,
+                _ => {
+                    return Err(format!(
+                        "Invalid 'payload_format' {0} in [mqtt] block, must be raw or json",
+                        payload_format
+                    ))
+                }
+            },
+            None => PayloadFormat::Raw,
+        };
         let adapters = config
             .get("bluetooth", "adapters")
             .ok_or("Missing 'adapters' in [bluetooth] block")?;
 
+        let only_known_devices = config
+            .getbool("bluetooth", "only_known_devices")?
+            .unwrap_or(false);
+        let inventory_file = config.get("bluetooth", "inventory_file");
+        let device_matcher = Config::parse_device_matcher(&config)?;
+        let shutdown_timeout = match config.getuint("bluetooth", "shutdown_timeout")? {
+            Some(0) => return Err("Invalid 'shutdown_timeout' in [bluetooth] block".to_string()),
+            Some(seconds) => Duration::from_secs(seconds),
+            None => Duration::from_secs(5),
+        };
+
+        let ha_discovery = config
+            .getbool("homeassistant", "discovery")?
+            .unwrap_or(true);
+        let ha_discovery_prefix = config
+            .get("homeassistant", "discovery_prefix")
+            .unwrap_or("homeassistant".to_string())
+            .trim_end_matches('/')
+            .to_string();
+        if ha_discovery_prefix.len() == 0 {
+            return Err("Invalid 'discovery_prefix' in [homeassistant] block".to_string());
+        }
+
+        let logging = Config::parse_logging(&config)?;
+
+        let device_defaults = Config::parse_device_config(
+            &config,
+            "bluetooth",
+            &DeviceConfig {
+                name: None,
+                alias: None,
+                enabled: true,
+                battery_poll_interval: Duration::from_secs(3600),
+                battery_low_threshold: 20,
+                rssi_interval: Duration::from_secs(60),
+                click_window: Duration::from_millis(400),
+                link_loss_alert: AlertLevel::Off,
+                alert_duration: None,
+                connect_timeout: Duration::from_secs(5),
+                reconnect_min_delay: Duration::from_secs(1),
+                reconnect_max_delay: Duration::from_secs(300),
+                reconnect_jitter: 0.2,
+                reconnect_reset_after: Duration::from_secs(60),
+                handover_margin: Some(10),
+                handover_dwell: Duration::from_secs(10),
+                expire_after: Some(Duration::from_secs(3600)),
+                expire_clear_retained: false,
+                silence_on_shutdown: true,
+                log_level: None,
+            },
+        )?;
+
+        let mut devices: HashMap<bluer::Address, DeviceConfig> = HashMap::new();
+        let mut aliases: HashSet<String> = HashSet::new();
+        for section in config.sections() {
+            let address = match section.strip_prefix("device ") {
+                Some(address) => address.trim(),
+                None => continue,
+            };
+            let address = match address.parse::<bluer::Address>() {
+                Ok(address) => address,
+                Err(_err) => return Err(format!("Invalid device address in [{0}]", section)),
+            };
+            let mut device_config =
+                Config::parse_device_config(&config, &section, &device_defaults)?;
+            device_config.name = config.get(&section, "name");
+            device_config.alias = config.get(&section, "alias");
+            device_config.enabled = config.getbool(&section, "enabled")?.unwrap_or(true);
+            device_config.log_level = match config.get(&section, "log_level") {
+                Some(level) => Some(parse_level(&level, "log_level", &section)?),
+                None => None,
+            };
+            if let Some(alias) = &device_config.alias {
+                if alias.len() == 0 || alias.contains(&['/', '+', '#', '{', '}']) {
+                    return Err(format!("Invalid 'alias' in [{0}] block", section));
+                }
+                if !aliases.insert(alias.clone()) {
+                    return Err(format!(
+                        "Duplicate 'alias' {0} in [{1}] block",
+                        alias, section
+                    ));
+                }
+            }
+            if devices.insert(address, device_config).is_some() {
+                return Err(format!("Duplicate device block [{0}]", section));
+            }
+        }
+
         let mqtt_port_u16 = match u16::try_from(mqtt_port) {
             Ok(mqtt_port_u16) => mqtt_port_u16,
             Err(_err) => return Err("Invalid mqtt port".to_string()),
@@ -49,10 +338,567 @@ impl Config {
         return Ok(Config {
             mqtt_host: mqtt_host,
             mqtt_port: mqtt_port_u16,
+            mqtt_username: mqtt_username,
+            mqtt_password: mqtt_password,
+            mqtt_password_file: mqtt_password_file,
+            mqtt_tls: mqtt_tls,
+            mqtt_client_id: mqtt_client_id,
+            mqtt_topics: mqtt_topics,
+            mqtt_payload_format: mqtt_payload_format,
             bt_adapters: adapters_list,
+            only_known_devices: only_known_devices,
+            inventory_file: inventory_file,
+            device_matcher: device_matcher,
+            shutdown_timeout: shutdown_timeout,
+            ha_discovery_prefix: if ha_discovery {
+                Some(ha_discovery_prefix)
+            } else {
+                None
+            },
+            logging: logging,
+            device_defaults: device_defaults,
+            devices: devices,
+        });
+    }
+
+    fn parse_logging(config: &Ini) -> Result<LoggingConfig, String> {
+        let level = match config.get("logging", "level") {
+            Some(level) => parse_level(&level, "level", "logging")?,
+            None => LevelFilter::Info,
+        };
+
+        // Module path and level: itag2mqttd::itag_swarm_manager=debug
+        let mut filters: Vec<(String, LevelFilter)> = Vec::new();
+        for filter in list_values(config.get("logging", "filters")) {
+            let (module, module_level) = match filter.split_once('=') {
+                Some((module, module_level)) => (module.trim(), module_level),
+                None => {
+                    return Err(format!(
+                        "Invalid filter {0} in 'filters' in [logging] block, must be module=level",
+                        filter
+                    ))
+                }
+            };
+            if module.len() == 0 {
+                return Err(format!(
+                    "Invalid filter {0} in 'filters' in [logging] block, must be module=level",
+                    filter
+                ));
+            }
+            let module_level = parse_level(module_level, "filters", "logging")?;
+            filters.push((module.to_string(), module_level));
+        }
+
+        let format = config
+            .get("logging", "format")
+            .map(|format| format.trim().to_ascii_lowercase());
+        let format = match format.as_deref() {
+            Some("text") | None => LogFormat::Text,
+            Some("json") => LogFormat::Json,
+            Some(_) => {
+                return Err("Invalid 'format' in [logging] block, must be text or json".to_string())
+            }
+        };
+        let timestamps = config.getbool("logging", "timestamps")?.unwrap_or(true);
+
+        return Ok(LoggingConfig {
+            level: level,
+            filters: filters,
+            format: format,
+            timestamps: timestamps,
+        });
+    }
+
+    fn parse_mqtt_tls(config: &Ini) -> Result<MqttTls, String> {
+        let ca_file = config.get("mqtt", "ca_file");
+        let ca = match &ca_file {
+            Some(ca_file) => {
+                let ca = read_file(ca_file, "ca_file")?;
+                if count_pem_certs(&ca, ca_file, "ca_file")? == 0 {
+                    return Err(format!(
+                        "No certificates in 'ca_file' {0} in [mqtt] block",
+                        ca_file
+                    ));
+                }
+                Some(ca)
+            }
+            None => None,
+        };
+
+        let client_auth_files =
+            match (
+                config.get("mqtt", "client_cert_file"),
+                config.get("mqtt", "client_key_file"),
+            ) {
+                (Some(cert_file), Some(key_file)) => Some((cert_file, key_file)),
+                (None, None) => None,
+                _ => return Err(
+                    "Both 'client_cert_file' and 'client_key_file' are required in [mqtt] block"
+                        .to_string(),
+                ),
+            };
+        let client_auth = match &client_auth_files {
+            Some((cert_file, key_file)) => {
+                let cert = read_file(cert_file, "client_cert_file")?;
+                if count_pem_certs(&cert, cert_file, "client_cert_file")? == 0 {
+                    return Err(format!(
+                        "No certificates in 'client_cert_file' {0} in [mqtt] block",
+                        cert_file
+                    ));
+                }
+                let key = read_file(key_file, "client_key_file")?;
+                match rustls_pemfile::private_key(&mut key.as_slice()) {
+                    Ok(Some(_)) => {}
+                    Ok(None) | Err(_) => {
+                        return Err(format!(
+                            "No private key in 'client_key_file' {0} in [mqtt] block",
+                            key_file
+                        ))
+                    }
+                }
+                Some((cert, key))
+            }
+            None => None,
+        };
+        if client_auth.is_some() && ca.is_none() {
+            return Err(
+                "Missing 'ca_file' in [mqtt] block, required with client certificate".to_string(),
+            );
+        }
+
+        return Ok(MqttTls {
+            ca: ca,
+            ca_file: ca_file,
+            client_auth: client_auth,
+            client_auth_files: client_auth_files,
+        });
+    }
+
+    fn parse_mqtt_topics(config: &Ini) -> Result<MqttTopics, String> {
+        let prefix = config
+            .get("mqtt", "topic_prefix")
+            .unwrap_or("itag".to_string())
+            .trim_end_matches('/')
+            .to_string();
+        if prefix.len() == 0 || prefix.contains(&['+', '#', '{', '}']) {
+            return Err("Invalid 'topic_prefix' in [mqtt] block".to_string());
+        }
+
+        let topic = |key: &str, default: &str, allowed: &[&str], required: &[&[&str]]| {
+            let template = config.get("mqtt", key).unwrap_or(default.to_string());
+            TopicTemplate::parse(key, &template, &prefix, allowed, required)
+        };
+        let device = ["address", "alias"];
+        let adapter = ["address", "alias", "adapter"];
+
+        let alert_set = config
+            .get("mqtt", "topic_alert_set")
+            .unwrap_or("{prefix}/{address}/alert/set".to_string());
+
+        return Ok(MqttTopics {
+            prefix: prefix.clone(),
+            bridge_state: topic("topic_bridge_state", "{prefix}/bridge/state", &[], &[])?,
+            presence: topic(
+                "topic_presence",
+                "{prefix}/{address}/presence",
+                &device,
+                &[&device],
+            )?,
+            button_click: topic(
+                "topic_button_click",
+                "{prefix}/{address}/button/click",
+                &device,
+                &[&device],
+            )?,
+            button_action: topic(
+                "topic_button_action",
+                "{prefix}/{address}/button/action",
+                &device,
+                &[&device],
+            )?,
+            battery: topic(
+                "topic_battery",
+                "{prefix}/{address}/battery",
+                &device,
+                &[&device],
+            )?,
+            rssi: topic("topic_rssi", "{prefix}/{address}/rssi", &device, &[&device])?,
+            adapter_rssi: topic(
+                "topic_adapter_rssi",
+                "{prefix}/{address}/adapter/{adapter}/rssi",
+                &adapter,
+                &[&device, &["adapter"]],
+            )?,
+            capabilities: topic(
+                "topic_capabilities",
+                "{prefix}/{address}/capabilities",
+                &device,
+                &[&device],
+            )?,
+            event: topic(
+                "topic_event",
+                "{prefix}/{address}/event",
+                &device,
+                &[&device],
+            )?,
+            diagnostics: topic(
+                "topic_diagnostics",
+                "{prefix}/{address}/diagnostics",
+                &device,
+                &[&device],
+            )?,
+            alert_set: TopicTemplate::parse_subscription("topic_alert_set", &alert_set, &prefix)?,
+            alert_result: topic(
+                "topic_alert_result",
+                "{prefix}/{address}/alert/result",
+                &device,
+                &[&device],
+            )?,
+        });
+    }
+
+    fn parse_device_matcher(config: &Ini) -> Result<DeviceMatcher, String> {
+        let mut device_matcher = DeviceMatcher::new();
+
+        let mut addresses: HashSet<bluer::Address> = HashSet::new();
+        for address in list_values(config.get("bluetooth", "match_addresses")) {
+            match address.parse::<bluer::Address>() {
+                Ok(address) => _ = addresses.insert(address),
+                Err(_err) => {
+                    return Err(format!(
+                        "Invalid address {0} in 'match_addresses' in [bluetooth] block",
+                        address
+                    ))
+                }
+            }
+        }
+        if addresses.len() != 0 {
+            device_matcher.add(Box::new(AddressMatcher::new(addresses)));
+        }
+
+        // Empty pattern disables name matching
+        let name = config
+            .get("bluetooth", "match_name")
+            .unwrap_or("^itag$".to_string());
+        if name.len() != 0 {
+            match NameMatcher::new(&name) {
+                Ok(matcher) => device_matcher.add(Box::new(matcher)),
+                Err(err) => {
+                    return Err(format!(
+                        "Invalid 'match_name' in [bluetooth] block: {0}",
+                        err
+                    ))
+                }
+            }
+        }
+
+        for service in list_values(config.get("bluetooth", "match_services")) {
+            match parse_uuid(&service) {
+                Some(uuid) => device_matcher.add(Box::new(ServiceMatcher::new(uuid))),
+                None => {
+                    return Err(format!(
+                        "Invalid UUID {0} in 'match_services' in [bluetooth] block",
+                        service
+                    ))
+                }
+            }
+        }
+
+        // Company id and optional data prefix, in hex: 0d00 or 0d00:0102
+        for manufacturer in list_values(config.get("bluetooth", "match_manufacturer")) {
+            let (company_id, data_prefix) = match manufacturer.split_once(':') {
+                Some((company_id, data_prefix)) => (company_id, data_prefix),
+                None => (manufacturer.as_str(), ""),
+            };
+            let company_id = match company_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+                true => u16::from_str_radix(company_id, 16).ok(),
+                false => None,
+            };
+            let data_prefix = parse_hex(data_prefix);
+            match (company_id, data_prefix) {
+                (Some(company_id), Some(data_prefix)) => {
+                    device_matcher.add(Box::new(ManufacturerMatcher::new(company_id, data_prefix)))
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid manufacturer {0} in 'match_manufacturer' in [bluetooth] block",
+                        manufacturer
+                    ))
+                }
+            }
+        }
+
+        return Ok(device_matcher);
+    }
+
+    fn parse_device_config(
+        config: &Ini,
+        section: &str,
+        defaults: &DeviceConfig,
+    ) -> Result<DeviceConfig, String> {
+        let battery_poll_interval = match config.getuint(section, "battery_poll_interval")? {
+            Some(0) => {
+                return Err(format!(
+                    "Invalid 'battery_poll_interval' in [{0}] block",
+                    section
+                ))
+            }
+            Some(seconds) => Duration::from_secs(seconds),
+            None => defaults.battery_poll_interval,
+        };
+        let battery_low_threshold = match config.getuint(section, "battery_low_threshold")? {
+            Some(percent) if percent <= 100 => percent as u8,
+            Some(_) => {
+                return Err(format!(
+                    "Invalid 'battery_low_threshold' in [{0}] block",
+                    section
+                ))
+            }
+            None => defaults.battery_low_threshold,
+        };
+
+        let rssi_interval = match config.getuint(section, "rssi_interval")? {
+            Some(0) => return Err(format!("Invalid 'rssi_interval' in [{0}] block", section)),
+            Some(seconds) => Duration::from_secs(seconds),
+            None => defaults.rssi_interval,
+        };
+        let click_window = match config.getuint(section, "click_window_ms")? {
+            Some(0) => return Err(format!("Invalid 'click_window_ms' in [{0}] block", section)),
+            Some(millis) => Duration::from_millis(millis),
+            None => defaults.click_window,
+        };
+        let link_loss_alert = match config.get(section, "link_loss_alert") {
+            Some(value) => AlertLevel::parse(&value).ok_or(format!(
+                "Invalid 'link_loss_alert' in [{0}] block, must be off, mild or high",
+                section
+            ))?,
+            None => defaults.link_loss_alert,
+        };
+        let alert_duration = match config.getuint(section, "alert_duration")? {
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => defaults.alert_duration,
+        };
+        let connect_timeout = match config.getuint(section, "connect_timeout")? {
+            Some(0) => return Err(format!("Invalid 'connect_timeout' in [{0}] block", section)),
+            Some(seconds) => Duration::from_secs(seconds),
+            None => defaults.connect_timeout,
+        };
+        let reconnect_min_delay = match config.getuint(section, "reconnect_min_delay")? {
+            Some(0) => {
+                return Err(format!(
+                    "Invalid 'reconnect_min_delay' in [{0}] block",
+                    section
+                ))
+            }
+            Some(seconds) => Duration::from_secs(seconds),
+            None => defaults.reconnect_min_delay,
+        };
+        let reconnect_max_delay = match config.getuint(section, "reconnect_max_delay")? {
+            Some(seconds) => Duration::from_secs(seconds),
+            None => defaults.reconnect_max_delay,
+        };
+        if reconnect_max_delay < reconnect_min_delay {
+            return Err(format!(
+                "'reconnect_max_delay' must not be less than 'reconnect_min_delay' in [{0}] block",
+                section
+            ));
+        }
+        let reconnect_jitter = match config.getuint(section, "reconnect_jitter")? {
+            Some(percent) if percent < 100 => percent as f64 / 100.0,
+            Some(_) => {
+                return Err(format!(
+                    "Invalid 'reconnect_jitter' in [{0}] block",
+                    section
+                ))
+            }
+            None => defaults.reconnect_jitter,
+        };
+        let reconnect_reset_after = match config.getuint(section, "reconnect_reset_after")? {
+            Some(seconds) => Duration::from_secs(seconds),
+            None => defaults.reconnect_reset_after,
+        };
+        let handover_margin = match config.getuint(section, "handover_margin")? {
+            Some(0) => None,
+            Some(decibels) if decibels <= 100 => Some(decibels as i16),
+            Some(_) => return Err(format!("Invalid 'handover_margin' in [{0}] block", section)),
+            None => defaults.handover_margin,
+        };
+        let handover_dwell = match config.getuint(section, "handover_dwell")? {
+            Some(seconds) => Duration::from_secs(seconds),
+            None => defaults.handover_dwell,
+        };
+        let expire_after = match config.getuint(section, "expire_after")? {
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => defaults.expire_after,
+        };
+        let expire_clear_retained = config
+            .getbool(section, "expire_clear_retained")?
+            .unwrap_or(defaults.expire_clear_retained);
+        let silence_on_shutdown = config
+            .getbool(section, "silence_on_shutdown")?
+            .unwrap_or(defaults.silence_on_shutdown);
+
+        return Ok(DeviceConfig {
+            name: defaults.name.clone(),
+            alias: defaults.alias.clone(),
+            enabled: defaults.enabled,
+            battery_poll_interval: battery_poll_interval,
+            battery_low_threshold: battery_low_threshold,
+            rssi_interval: rssi_interval,
+            click_window: click_window,
+            link_loss_alert: link_loss_alert,
+            alert_duration: alert_duration,
+            connect_timeout: connect_timeout,
+            reconnect_min_delay: reconnect_min_delay,
+            reconnect_max_delay: reconnect_max_delay,
+            reconnect_jitter: reconnect_jitter,
+            reconnect_reset_after: reconnect_reset_after,
+            handover_margin: handover_margin,
+            handover_dwell: handover_dwell,
+            expire_after: expire_after,
+            expire_clear_retained: expire_clear_retained,
+            silence_on_shutdown: silence_on_shutdown,
+            log_level: defaults.log_level,
         });
     }
 
+    /// Effective config in the format it is read in, with every default filled in.
+    /// Secrets are hidden, and TLS material is only summarized as it is not kept by path.
+    pub fn to_ini(&self) -> String {
+        let mut lines: Vec<String> = vec!["[mqtt]".to_string()];
+        lines.push(format!("host={0}", self.mqtt_host));
+        lines.push(format!("port={0}", self.mqtt_port));
+        if let Some(username) = &self.mqtt_username {
+            lines.push(format!("username={0}", username));
+        }
+        // Inline password is not printed
+        match (&self.mqtt_password_file, &self.mqtt_password) {
+            (Some(password_file), _) => lines.push(format!("password_file={0}", password_file)),
+            (None, Some(_)) => lines.push("# password is set".to_string()),
+            (None, None) => {}
+        }
+        lines.push(format!("tls={0}", self.mqtt_tls.is_some()));
+        if let Some(tls) = &self.mqtt_tls {
+            if let Some(ca_file) = &tls.ca_file {
+                lines.push(format!("ca_file={0}", ca_file));
+            }
+            if let Some((cert_file, key_file)) = &tls.client_auth_files {
+                lines.push(format!("client_cert_file={0}", cert_file));
+                lines.push(format!("client_key_file={0}", key_file));
+            }
+        }
+        lines.push(format!("client_id={0}", self.mqtt_client_id));
+        lines.push(format!(
+            "payload_format={0}",
+            match self.mqtt_payload_format {
+                PayloadFormat::Raw => "raw",
+                PayloadFormat::Json => "json",
+            }
+        ));
+        let topics = &self.mqtt_topics;
+        lines.push(format!("topic_prefix={0}", topics.prefix));
+        for (key, topic) in [
+            ("topic_bridge_state", &topics.bridge_state),
+            ("topic_presence", &topics.presence),
+            ("topic_button_click", &topics.button_click),
+            ("topic_button_action", &topics.button_action),
+            ("topic_battery", &topics.battery),
+            ("topic_rssi", &topics.rssi),
+            ("topic_adapter_rssi", &topics.adapter_rssi),
+            ("topic_capabilities", &topics.capabilities),
+            ("topic_event", &topics.event),
+            ("topic_diagnostics", &topics.diagnostics),
+            ("topic_alert_set", &topics.alert_set),
+            ("topic_alert_result", &topics.alert_result),
+        ] {
+            lines.push(format!("{0}={1}", key, topic.as_str()));
+        }
+
+        lines.push(String::new());
+        lines.push("[bluetooth]".to_string());
+        lines.push(format!("adapters={0}", self.bt_adapters.join(",")));
+        lines.push(format!("only_known_devices={0}", self.only_known_devices));
+        if let Some(inventory_file) = &self.inventory_file {
+            lines.push(format!("inventory_file={0}", inventory_file));
+        }
+        // Matchers of one kind are listed together, like they are configured
+        let mut match_entries: Vec<(&'static str, Vec<String>)> = Vec::new();
+        for (key, value) in self.device_matcher.config_entries() {
+            match match_entries.iter_mut().find(|(other, _)| *other == key) {
+                Some((_, values)) => values.push(value),
+                None => match_entries.push((key, vec![value])),
+            }
+        }
+        if !match_entries.iter().any(|(key, _)| *key == "match_name") {
+            lines.push("match_name=".to_string());
+        }
+        for (key, values) in match_entries {
+            lines.push(format!("{0}={1}", key, values.join(",")));
+        }
+        lines.push(format!(
+            "shutdown_timeout={0}",
+            self.shutdown_timeout.as_secs()
+        ));
+        lines.extend(device_config_lines(&self.device_defaults));
+
+        lines.push(String::new());
+        lines.push("[homeassistant]".to_string());
+        lines.push(format!("discovery={0}", self.ha_discovery_prefix.is_some()));
+        if let Some(prefix) = &self.ha_discovery_prefix {
+            lines.push(format!("discovery_prefix={0}", prefix));
+        }
+
+        lines.push(String::new());
+        lines.push("[logging]".to_string());
+        lines.push(format!("level={0}", level_name(self.logging.level)));
+        let filters: Vec<String> = self
+            .logging
+            .filters
+            .iter()
+            .map(|(module, level)| format!("{0}={1}", module, level_name(*level)))
+            .collect();
+        lines.push(format!("filters={0}", filters.join(",")));
+        lines.push(format!(
+            "format={0}",
+            match self.logging.format {
+                LogFormat::Text => "text",
+                LogFormat::Json => "json",
+            }
+        ));
+        lines.push(format!("timestamps={0}", self.logging.timestamps));
+
+        let mut addresses: Vec<&bluer::Address> = self.devices.keys().collect();
+        addresses.sort();
+        for address in addresses {
+            let device_config = &self.devices[address];
+            lines.push(String::new());
+            lines.push(format!("[device {0}]", address));
+            if let Some(name) = &device_config.name {
+                lines.push(format!("name={0}", name));
+            }
+            if let Some(alias) = &device_config.alias {
+                lines.push(format!("alias={0}", alias));
+            }
+            lines.push(format!("enabled={0}", device_config.enabled));
+            if let Some(level) = device_config.log_level {
+                lines.push(format!("log_level={0}", level_name(level)));
+            }
+            lines.extend(device_config_lines(device_config));
+        }
+
+        lines.push(String::new());
+        return lines.join("\n");
+    }
+
+    pub fn device_config(&self, address: &bluer::Address) -> DeviceConfig {
+        return match self.devices.get(address) {
+            Some(device_config) => device_config.clone(),
+            None => self.device_defaults.clone(),
+        };
+    }
+
     pub fn is_adapter_allowed(&self, adapter_name: &str) -> bool {
         // If there is no whitelist, then every adapter is accepted
         if self.bt_adapters.len() == 0 {
@@ -66,3 +912,124 @@ impl Config {
         return false;
     }
 }
+
+/// Keys that can be set both in [bluetooth] and [device] blocks.
+fn device_config_lines(device_config: &DeviceConfig) -> Vec<String> {
+    let seconds = |duration: Option<Duration>| match duration {
+        Some(duration) => duration.as_secs(),
+        None => 0,
+    };
+    return vec![
+        format!(
+            "battery_poll_interval={0}",
+            device_config.battery_poll_interval.as_secs()
+        ),
+        format!(
+            "battery_low_threshold={0}",
+            device_config.battery_low_threshold
+        ),
+        format!("rssi_interval={0}", device_config.rssi_interval.as_secs()),
+        format!(
+            "click_window_ms={0}",
+            device_config.click_window.as_millis()
+        ),
+        format!(
+            "link_loss_alert={0}",
+            device_config.link_loss_alert.as_str()
+        ),
+        format!("alert_duration={0}", seconds(device_config.alert_duration)),
+        format!(
+            "connect_timeout={0}",
+            device_config.connect_timeout.as_secs()
+        ),
+        format!(
+            "reconnect_min_delay={0}",
+            device_config.reconnect_min_delay.as_secs()
+        ),
+        format!(
+            "reconnect_max_delay={0}",
+            device_config.reconnect_max_delay.as_secs()
+        ),
+        format!(
+            "reconnect_jitter={0}",
+            (device_config.reconnect_jitter * 100.0).round()
+        ),
+        format!(
+            "reconnect_reset_after={0}",
+            device_config.reconnect_reset_after.as_secs()
+        ),
+        format!(
+            "handover_margin={0}",
+            device_config.handover_margin.unwrap_or(0)
+        ),
+        format!("handover_dwell={0}", device_config.handover_dwell.as_secs()),
+        format!("expire_after={0}", seconds(device_config.expire_after)),
+        format!(
+            "expire_clear_retained={0}",
+            device_config.expire_clear_retained
+        ),
+        format!("silence_on_shutdown={0}", device_config.silence_on_shutdown),
+    ];
+}
+
+fn level_name(level: LevelFilter) -> String {
+    return level.to_string().to_ascii_lowercase();
+}
+
+/// Splits comma separated value, skipping empty items.
+fn list_values(value: Option<String>) -> Vec<String> {
+    return value
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| item.len() != 0)
+        .collect();
+}
+
+fn parse_level(value: &str, key: &str, section: &str) -> Result<LevelFilter, String> {
+    return match LevelFilter::from_str(value.trim()) {
+        Ok(level) => Ok(level),
+        Err(_err) => Err(format!(
+            "Invalid level {0} in '{1}' in [{2}] block, must be off, error, warn, info, debug or trace",
+            value.trim(),
+            key,
+            section
+        )),
+    };
+}
+
+/// Parses bytes written in hex, two digits each, e.g. `0102`.
+pub fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    // from_str_radix() would also accept a sign
+    if value.len() % 2 != 0 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    return (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect();
+}
+
+fn read_file(path: &str, key: &str) -> Result<Vec<u8>, String> {
+    return match std::fs::read(path) {
+        Ok(contents) => Ok(contents),
+        Err(err) => Err(format!(
+            "Cannot read '{0}' {1} in [mqtt] block: {2}",
+            key, path, err
+        )),
+    };
+}
+
+fn count_pem_certs(pem: &[u8], path: &str, key: &str) -> Result<usize, String> {
+    let mut count = 0;
+    for cert in rustls_pemfile::certs(&mut &pem[..]) {
+        if let Err(err) = cert {
+            return Err(format!(
+                "Invalid certificate in '{0}' {1} in [mqtt] block: {2}",
+                key, path, err
+            ));
+        }
+        count += 1;
+    }
+    return Ok(count);
+}
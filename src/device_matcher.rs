@@ -0,0 +1,208 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::bluetooth::Device;
+use bluer::Uuid;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+
+/// What is known of a discovered device, for deciding whether it is a tag.
+pub struct DeviceInfo {
+    pub address: bluer::Address,
+    pub name: Option<String>,
+    pub uuids: HashSet<Uuid>,
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+impl DeviceInfo {
+    /// Reads the advertised properties BlueZ has cached. Does not connect.
+    pub async fn read(device: &dyn Device) -> Result<DeviceInfo, bluer::Error> {
+        return Ok(DeviceInfo {
+            address: device.address(),
+            name: device.name().await?,
+            uuids: device.uuids().await?,
+            manufacturer_data: device.manufacturer_data().await?,
+        });
+    }
+}
+
+/// A rule for recognizing tags. Returns the reason when the device matches.
+pub trait Matcher: Send + Sync {
+    fn matches(&self, device: &DeviceInfo) -> Option<String>;
+
+    /// Config key and value that create this matcher.
+    fn config_entry(&self) -> (&'static str, String);
+}
+
+/// Matches the advertised name, trimmed. Devices without a name match as an empty name.
+pub struct NameMatcher {
+    regex: Regex,
+}
+
+impl NameMatcher {
+    /// Case insensitive.
+    pub fn new(pattern: &str) -> Result<NameMatcher, regex::Error> {
+        let regex = regex::RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()?;
+        return Ok(NameMatcher { regex: regex });
+    }
+}
+
+impl Matcher for NameMatcher {
+    fn matches(&self, device: &DeviceInfo) -> Option<String> {
+        let name = device.name.as_deref().unwrap_or("").trim();
+        if !self.regex.is_match(name) {
+            return None;
+        }
+        return Some(format!("name '{0}' matches '{1}'", name, self.regex));
+    }
+
+    fn config_entry(&self) -> (&'static str, String) {
+        return ("match_name", self.regex.to_string());
+    }
+}
+
+/// Matches devices that advertise the service.
+pub struct ServiceMatcher {
+    uuid: Uuid,
+}
+
+impl ServiceMatcher {
+    pub fn new(uuid: Uuid) -> ServiceMatcher {
+        return ServiceMatcher { uuid: uuid };
+    }
+}
+
+impl Matcher for ServiceMatcher {
+    fn matches(&self, device: &DeviceInfo) -> Option<String> {
+        if !device.uuids.contains(&self.uuid) {
+            return None;
+        }
+        return Some(format!("advertises service {0}", self.uuid));
+    }
+
+    fn config_entry(&self) -> (&'static str, String) {
+        return ("match_services", self.uuid.to_string());
+    }
+}
+
+/// Matches manufacturer data by company id, and optionally by the start of the data.
+pub struct ManufacturerMatcher {
+    company_id: u16,
+    data_prefix: Vec<u8>,
+}
+
+impl ManufacturerMatcher {
+    pub fn new(company_id: u16, data_prefix: Vec<u8>) -> ManufacturerMatcher {
+        return ManufacturerMatcher {
+            company_id: company_id,
+            data_prefix: data_prefix,
+        };
+    }
+}
+
+impl Matcher for ManufacturerMatcher {
+    fn matches(&self, device: &DeviceInfo) -> Option<String> {
+        let data = device.manufacturer_data.get(&self.company_id)?;
+        if !data.starts_with(&self.data_prefix) {
+            return None;
+        }
+        return Some(format!(
+            "manufacturer data of company {0:#06x}",
+            self.company_id
+        ));
+    }
+
+    fn config_entry(&self) -> (&'static str, String) {
+        let mut value = format!("{0:04x}", self.company_id);
+        if self.data_prefix.len() != 0 {
+            value.push(':');
+            for byte in &self.data_prefix {
+                value.push_str(&format!("{0:02x}", byte));
+            }
+        }
+        return ("match_manufacturer", value);
+    }
+}
+
+/// Matches explicitly listed addresses.
+pub struct AddressMatcher {
+    addresses: HashSet<bluer::Address>,
+}
+
+impl AddressMatcher {
+    pub fn new(addresses: HashSet<bluer::Address>) -> AddressMatcher {
+        return AddressMatcher {
+            addresses: addresses,
+        };
+    }
+}
+
+impl Matcher for AddressMatcher {
+    fn matches(&self, device: &DeviceInfo) -> Option<String> {
+        if !self.addresses.contains(&device.address) {
+            return None;
+        }
+        return Some("address is listed in match_addresses".to_string());
+    }
+
+    fn config_entry(&self) -> (&'static str, String) {
+        let mut addresses: Vec<String> = self
+            .addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect();
+        addresses.sort();
+        return ("match_addresses", addresses.join(","));
+    }
+}
+
+/// Set of matchers. Device is a tag if any of them matches.
+pub struct DeviceMatcher {
+    matchers: Vec<Box<dyn Matcher>>,
+}
+
+impl DeviceMatcher {
+    pub fn new() -> DeviceMatcher {
+        return DeviceMatcher {
+            matchers: Vec::new(),
+        };
+    }
+
+    pub fn add(&mut self, matcher: Box<dyn Matcher>) {
+        self.matchers.push(matcher);
+    }
+
+    /// Config entries of the matchers, in the order they were added.
+    pub fn config_entries(&self) -> Vec<(&'static str, String)> {
+        return self
+            .matchers
+            .iter()
+            .map(|matcher| matcher.config_entry())
+            .collect();
+    }
+
+    /// Returns the reason of the first match.
+    pub fn matches(&self, device: &DeviceInfo) -> Option<String> {
+        return self
+            .matchers
+            .iter()
+            .find_map(|matcher| matcher.matches(device));
+    }
+}
+
+/// Parses full UUID, or 16-bit one in short hex form such as `ffe0`.
+pub fn parse_uuid(value: &str) -> Option<Uuid> {
+    let value = value.trim();
+    if value.len() == 4 {
+        if !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let short = u16::from_str_radix(value, 16).ok()?;
+        return Some(Uuid::from_u128(
+            0x00000000_0000_1000_8000_00805f9b34fb | ((short as u128) << 96),
+        ));
+    }
+    return Uuid::parse_str(value).ok();
+}
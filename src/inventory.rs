@@ -0,0 +1,83 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::config::Config;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// Tags adopted as our own, besides those with a [device] block. Persisted as one
+/// address per line, so that the tags stay known across restarts.
+pub struct Inventory {
+    path: Option<String>,
+    addresses: Mutex<HashSet<bluer::Address>>,
+}
+
+impl Inventory {
+    /// Loads the inventory from file. Missing file is an empty inventory, and
+    /// without a path the inventory is not persisted at all.
+    pub fn load(path: Option<&str>) -> Result<Inventory, String> {
+        let mut addresses: HashSet<bluer::Address> = HashSet::new();
+
+        if let Some(path) = path {
+            let contents = match std::fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(format!("Cannot read inventory {0}: {1}", path, err)),
+            };
+            for (line_number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.len() == 0 || line.starts_with('#') {
+                    continue;
+                }
+                match line.parse::<bluer::Address>() {
+                    Ok(address) => _ = addresses.insert(address),
+                    Err(_err) => {
+                        return Err(format!(
+                            "Invalid address on line {0} of inventory {1}",
+                            line_number + 1,
+                            path
+                        ))
+                    }
+                }
+            }
+        }
+
+        return Ok(Inventory {
+            path: path.map(|path| path.to_string()),
+            addresses: Mutex::new(addresses),
+        });
+    }
+
+    pub fn contains(&self, address: &bluer::Address) -> bool {
+        return self.addresses.lock().unwrap().contains(address);
+    }
+
+    /// Adds the tag to the inventory, and appends it to the file if it is new.
+    /// Returns false if the tag already was in the inventory.
+    pub fn insert(&self, address: &bluer::Address) -> Result<bool, String> {
+        if !self.addresses.lock().unwrap().insert(*address) {
+            return Ok(false);
+        }
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(true),
+        };
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", address));
+        if let Err(err) = result {
+            return Err(format!("Cannot write inventory {0}: {1}", path, err));
+        }
+        return Ok(true);
+    }
+}
+
+/// Whether the tag is one of ours: configured in a [device] block, or in the inventory.
+pub fn is_known(config: &Config, inventory: &Inventory, address: &bluer::Address) -> bool {
+    return config.devices.contains_key(address) || inventory.contains(address);
+}
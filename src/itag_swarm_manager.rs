@@ -1,36 +1,99 @@
 // Author: Jarkko Pöyry
 // See LICENSE for License
 
+pub mod click_classifier;
 mod device_actor;
+mod handover_tracker;
+pub mod reconnect_backoff;
 
+use crate::bluetooth::{
+    start_discovery, Adapter, AdapterEvent, Backend, Device, DeviceEvent, EventStream,
+};
 use crate::config::Config;
+use crate::device_matcher::DeviceInfo;
+use crate::inventory::{is_known, Inventory};
 use crate::itag_swarm_manager::device_actor::DeviceActor;
-use crate::mqtt_client::MqttClient;
+use crate::mqtt_client::{MqttClient, MqttEvent};
+use crate::sd_notify::SdNotify;
+use log::{debug, info, warn};
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::future::Future;
 use std::sync::Arc;
 use std::sync::Mutex;
+use tokio::sync::oneshot::error::TryRecvError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, timeout, Duration, Instant};
 use tokio_stream::StreamExt;
 
+/// How often devices are checked for expiry.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Unknown tag that has not been seen for this long is logged again when it returns.
+const IGNORED_EXPIRY: Duration = Duration::from_secs(3600);
+/// How often adapter poll tasks report being alive, even without device events.
+const ADAPTER_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// How often status is reported to systemd when the watchdog is disabled.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Adapter in use, and the task polling it for devices.
+struct ManagedAdapter {
+    address: bluer::Address,
+    poll_task: JoinHandle<()>,
+    /// When the poll task was last responsive.
+    heartbeat: Arc<Mutex<Instant>>,
+}
+
 pub struct ITagSwarmManager {
     actors: Mutex<HashMap<bluer::Address, Arc<DeviceActor>>>,
     config: Config,
+    inventory: Inventory,
+    /// Unknown tags that have been logged as ignored, and when they were last seen.
+    ignored: Mutex<HashMap<bluer::Address, Instant>>,
+    /// Completion of forgetting expired tags. New actor of a tag that comes back waits
+    /// for it.
+    forgotten: Mutex<HashMap<bluer::Address, oneshot::Receiver<()>>>,
     mqttc: Arc<MqttClient>,
+    notify: SdNotify,
 }
 
 impl ITagSwarmManager {
-    pub fn new(config: Config, mqttc: MqttClient) -> ITagSwarmManager {
+    pub fn new(
+        config: Config,
+        inventory: Inventory,
+        mqttc: MqttClient,
+        notify: SdNotify,
+    ) -> ITagSwarmManager {
         return ITagSwarmManager {
             actors: Mutex::new(HashMap::new()),
             config: config,
+            inventory: inventory,
+            ignored: Mutex::new(HashMap::new()),
+            forgotten: Mutex::new(HashMap::new()),
             mqttc: Arc::new(mqttc),
+            notify: notify,
         };
     }
 
-    pub async fn run_async(self, session: bluer::Session) {
+    /// Manages tags until `shutdown` completes, and then disconnects them and the
+    /// MQTT client.
+    pub async fn run_async(
+        self,
+        backend: Arc<dyn Backend>,
+        mqtt_events: mpsc::UnboundedReceiver<MqttEvent>,
+        shutdown: impl Future<Output = ()>,
+    ) {
         let manager = Arc::new(self);
 
-        let adapter_names = match session.adapter_names().await {
+        {
+            let manager = manager.clone();
+            tokio::spawn(async move { poll_mqtt_events(manager, mqtt_events).await });
+        }
+        {
+            let manager = manager.clone();
+            tokio::spawn(async move { expire_devices(manager).await });
+        }
+
+        let adapter_names = match backend.adapter_names().await {
             Ok(session) => session,
             Err(err) => {
                 panic!("Cannot enumerate bluetooth adapters {0}", err);
@@ -38,146 +101,338 @@ impl ITagSwarmManager {
         };
 
         if adapter_names.len() == 0 {
-            println!("Warning. No bluetooth adapters found");
+            warn!("No bluetooth adapters found");
         }
 
-        let stream = match session.events().await {
+        let stream = match backend.adapter_events().await {
             Ok(stream) => stream,
             Err(err) => {
                 panic!("Cannot open bluetooth adapters event stream {0}", err);
             }
         };
 
-        let mut adapters: HashSet<String> = HashSet::new();
+        let mut adapters: HashMap<String, ManagedAdapter> = HashMap::new();
 
         // Apply already existing adapters
         for adapter_name in adapter_names {
-            handle_new_adapter(manager.clone(), &session, &mut adapters, adapter_name).await;
+            handle_new_adapter(manager.clone(), &*backend, &mut adapters, adapter_name).await;
         }
 
         if adapters.len() == 0 {
-            println!("Warning. No matching bluetooth adapters present. Adapters may appear later with hot plugging.");
+            warn!("No matching bluetooth adapters present. Adapters may appear later with hot plugging.");
         }
 
         // Poll for adapter updates
         tokio::pin!(stream);
-        while let Some(adapter_event) = stream.next().await {
-            match adapter_event {
-                bluer::SessionEvent::AdapterAdded(adapter_name) => {
-                    handle_new_adapter(manager.clone(), &session, &mut adapters, adapter_name)
-                        .await;
-                }
-                bluer::SessionEvent::AdapterRemoved(adapter_name) => {
-                    handle_remove_adapter(&mut adapters, adapter_name).await;
-                }
+        tokio::pin!(shutdown);
+        let mut notify_interval = interval(
+            manager
+                .notify
+                .watchdog_interval()
+                .unwrap_or(STATUS_INTERVAL),
+        );
+        let mut last_status = String::new();
+        let mut stalled_adapter = None;
+        let mut is_mqtt_stalled = false;
+        loop {
+            tokio::select! {
+                _ = notify_interval.tick() => {
+                    notify_systemd(
+                        &manager,
+                        &adapters,
+                        &mut last_status,
+                        &mut stalled_adapter,
+                        &mut is_mqtt_stalled,
+                    );
+                },
+                adapter_event = stream.next() => {
+                    match adapter_event {
+                        Some(AdapterEvent::Added(adapter_name)) => {
+                            handle_new_adapter(manager.clone(), &*backend, &mut adapters, adapter_name)
+                                .await;
+                        }
+                        Some(AdapterEvent::Removed(adapter_name)) => {
+                            handle_remove_adapter(&manager, &mut adapters, adapter_name).await;
+                        }
+                        None => break,
+                    }
+                },
+                _ = &mut shutdown => break,
             }
         }
+
+        // Whatever is left undone by the timeout is left to BlueZ and the Last Will
+        manager.notify.stopping();
+        let shutdown_timeout = manager.config.shutdown_timeout;
+        if timeout(shutdown_timeout, handle_shutdown(&manager, adapters))
+            .await
+            .is_err()
+        {
+            warn!(
+                "Shutdown did not finish in {0} seconds",
+                shutdown_timeout.as_secs()
+            );
+        }
     }
 }
 
-async fn handle_new_adapter(
-    manager: Arc<ITagSwarmManager>,
-    session: &bluer::Session,
-    adapters: &mut HashSet<String>,
-    adapter_name: String,
+/// Stops discovery, disconnects every tag and marks them and the daemon offline.
+async fn handle_shutdown(manager: &ITagSwarmManager, adapters: HashMap<String, ManagedAdapter>) {
+    for adapter in adapters.values() {
+        adapter.poll_task.abort();
+    }
+
+    let actors: Vec<Arc<DeviceActor>> = manager
+        .actors
+        .lock()
+        .unwrap()
+        .drain()
+        .map(|(_, actor)| actor)
+        .collect();
+    let mut pending = Vec::new();
+    for actor in &actors {
+        pending.push(actor.shutdown());
+    }
+    for done in pending {
+        let _ = done.await;
+    }
+
+    manager.mqttc.disconnect().await;
+}
+
+/// Reports status to systemd, and pets the watchdog if adapters and MQTT are alive.
+/// Stalls are logged when they begin and end, not on every call.
+fn notify_systemd(
+    manager: &ITagSwarmManager,
+    adapters: &HashMap<String, ManagedAdapter>,
+    last_status: &mut String,
+    stalled_adapter: &mut Option<String>,
+    is_mqtt_stalled: &mut bool,
 ) {
-    if !manager.config.is_adapter_allowed(&adapter_name) {
-        return;
+    let (tag_count, connected_count) = {
+        let actors = manager.actors.lock().unwrap();
+        let connected_count = actors.values().filter(|actor| actor.is_present()).count();
+        (actors.len(), connected_count)
+    };
+    let status = format!(
+        "Adapters: {0}, tags: {1}, connected: {2}, MQTT: {3}",
+        adapters.len(),
+        tag_count,
+        connected_count,
+        if manager.mqttc.is_connected() {
+            "connected"
+        } else {
+            "disconnected"
+        }
+    );
+    if status != *last_status {
+        manager.notify.status(&status);
+        *last_status = status;
     }
 
-    let adapter = match session.adapter(&adapter_name) {
-        Ok(adapter) => adapter,
-        Err(err) => {
-            println!(
-                "Warning! Cannot get bluetooth adapter {0}: {1}",
-                adapter_name, err
-            );
-            return;
+    let stalled = adapters
+        .iter()
+        .find(|(_, adapter)| {
+            adapter.poll_task.is_finished()
+                || adapter.heartbeat.lock().unwrap().elapsed() > ADAPTER_HEARTBEAT_INTERVAL * 3
+        })
+        .map(|(adapter_name, _)| adapter_name.clone());
+    if stalled != *stalled_adapter {
+        match (&stalled, &*stalled_adapter) {
+            (Some(adapter_name), _) => {
+                warn!(adapter_name:% = adapter_name; "Adapter is not responding")
+            }
+            (None, Some(adapter_name)) => {
+                info!(adapter_name:% = adapter_name; "Adapter is responding again")
+            }
+            (None, None) => {}
         }
-    };
+        *stalled_adapter = stalled;
+    }
+    if stalled_adapter.is_some() {
+        return;
+    }
 
-    match adapter.set_powered(true).await {
-        Ok(()) => {}
-        Err(err) => {
-            println!(
-                "Warning! Cannot set bluetooth adapter {0} powered: {1}",
-                adapter_name, err
-            );
+    let is_stalled = !manager.mqttc.is_making_progress();
+    if is_stalled != *is_mqtt_stalled {
+        if is_stalled {
+            warn!("MQTT client is not responding");
+        } else {
+            info!("MQTT client is responding again");
         }
-    };
+        *is_mqtt_stalled = is_stalled;
+    }
+    if is_stalled {
+        return;
+    }
+    if manager.notify.watchdog_interval().is_some() {
+        manager.notify.watchdog();
+    }
+}
 
-    let address = match adapter.address().await {
-        Ok(address) => address,
-        Err(err) => {
-            println!("Warning! Cannot get bluetooth adapter address {0}", err);
-            return;
+async fn poll_mqtt_events(
+    manager: Arc<ITagSwarmManager>,
+    mut mqtt_events: mpsc::UnboundedReceiver<MqttEvent>,
+) {
+    while let Some(event) = mqtt_events.recv().await {
+        match event {
+            MqttEvent::Connected => {
+                // Bluetooth is up by the time MQTT events are handled
+                manager.notify.ready();
+            }
+            MqttEvent::HomeAssistantOnline => {
+                handle_home_assistant_online(&manager).await;
+            }
+            MqttEvent::AlertCommand { device_id, payload } => {
+                handle_alert_command(&manager, device_id, payload);
+            }
         }
-    };
+    }
+}
 
-    // already inserted?
-    if !adapters.insert(adapter_name.clone()) {
-        return;
+/// Forgets devices that have been gone for long enough. A forgotten device gets a new
+/// actor if it comes back.
+async fn expire_devices(manager: Arc<ITagSwarmManager>) {
+    loop {
+        sleep(EXPIRY_CHECK_INTERVAL).await;
+        manager
+            .ignored
+            .lock()
+            .unwrap()
+            .retain(|_, last_seen| last_seen.elapsed() < IGNORED_EXPIRY);
+        let mut actors = manager.actors.lock().unwrap();
+        let mut forgotten = manager.forgotten.lock().unwrap();
+        forgotten.retain(|_, done| done.try_recv() == Err(TryRecvError::Empty));
+        actors.retain(|device_address, actor| {
+            if !actor.is_expired() {
+                return true;
+            }
+            info!(device:% = device_address; "Device has not been seen for a while");
+            forgotten.insert(*device_address, actor.forget());
+            return false;
+        });
     }
+}
 
-    println!("Found adapter {} ({})", adapter_name, address);
-    tokio::spawn(async move { poll_adapter(manager, address, adapter).await });
+async fn handle_home_assistant_online(manager: &ITagSwarmManager) {
+    info!("Home Assistant came online, re-announcing devices");
+
+    // Awaited one by one, as announcements of many tags do not fit in the MQTT queue at once
+    let actors: Vec<Arc<DeviceActor>> = manager.actors.lock().unwrap().values().cloned().collect();
+    for actor in &actors {
+        actor.publish_discovery().await;
+    }
+
+    // Give Home Assistant a moment to create the entities before sending them state
+    sleep(Duration::from_millis(1000)).await;
+    for actor in &actors {
+        actor.announce_state().await;
+    }
 }
 
-async fn handle_remove_adapter(adapters: &mut HashSet<String>, adapter_name: String) {
-    // poll tasks will clear theselves automatically
-    adapters.remove(&adapter_name);
+fn handle_alert_command(manager: &ITagSwarmManager, device_id: [u8; 6], payload: String) {
+    let actors = manager.actors.lock().unwrap();
+    match actors.get(&bluer::Address::new(device_id)) {
+        Some(actor) => actor.alert(payload),
+        None => manager
+            .mqttc
+            .publish_alert_result(&device_id, Err("unknown device".to_string())),
+    }
 }
 
-async fn poll_adapter(
+async fn handle_new_adapter(
     manager: Arc<ITagSwarmManager>,
-    adapter_address: bluer::Address,
-    adapter: bluer::Adapter,
+    backend: &dyn Backend,
+    adapters: &mut HashMap<String, ManagedAdapter>,
+    adapter_name: String,
 ) {
-    let adapter = Arc::new(adapter);
+    // already inserted?
+    if adapters.contains_key(&adapter_name) {
+        return;
+    }
 
-    // Poll only LE devices
-    let filter = bluer::DiscoveryFilter {
-        transport: bluer::DiscoveryTransport::Le,
-        ..Default::default()
+    let (adapter, stream) = match start_discovery(&manager.config, backend, &adapter_name).await {
+        Some(discovery) => discovery,
+        None => return,
     };
 
-    match adapter.set_discovery_filter(filter).await {
-        Ok(()) => {}
+    let address = match adapter.address().await {
+        Ok(address) => address,
         Err(err) => {
-            println!("Warning! Couldn't set LE-only discovery filter: {}", err);
+            warn!(adapter_name:% = adapter_name; "Cannot get bluetooth adapter address: {0}", err);
+            return;
         }
     };
 
-    let stream = match adapter.discover_devices_with_changes().await {
-        Ok(stream) => stream,
-        Err(err) => {
-            println!(
-                "Warning! Cannot get bluetooth adapter event stream: {}",
-                err
-            );
-            return;
-        }
+    info!(adapter:% = address, adapter_name:% = adapter_name; "Found adapter");
+    let heartbeat = Arc::new(Mutex::new(Instant::now()));
+    let poll_heartbeat = heartbeat.clone();
+    let poll_task = tokio::spawn(async move {
+        poll_adapter(manager, address, adapter, stream, poll_heartbeat).await
+    });
+    adapters.insert(
+        adapter_name,
+        ManagedAdapter {
+            address: address,
+            poll_task: poll_task,
+            heartbeat: heartbeat,
+        },
+    );
+}
+
+async fn handle_remove_adapter(
+    manager: &ITagSwarmManager,
+    adapters: &mut HashMap<String, ManagedAdapter>,
+    adapter_name: String,
+) {
+    let adapter = match adapters.remove(&adapter_name) {
+        Some(adapter) => adapter,
+        None => return,
     };
+    info!(adapter:% = adapter.address, adapter_name:% = adapter_name; "Lost adapter");
+    adapter.poll_task.abort();
+
+    // Tags seen through the adapter are gone with it
+    let actors = manager.actors.lock().unwrap();
+    for actor in actors.values() {
+        actor.adapter_removed(adapter.address);
+    }
+}
 
+async fn poll_adapter(
+    manager: Arc<ITagSwarmManager>,
+    adapter_address: bluer::Address,
+    adapter: Arc<dyn Adapter>,
+    stream: EventStream<DeviceEvent>,
+    heartbeat: Arc<Mutex<Instant>>,
+) {
     tokio::pin!(stream);
-    while let Some(event) = stream.next().await {
-        match event {
-            bluer::AdapterEvent::DeviceAdded(device_address) => {
-                handle_device_updated(&manager, adapter_address, adapter.clone(), device_address)
-                    .await;
-            }
-            bluer::AdapterEvent::DeviceRemoved(device_address) => {
-                handle_device_removed(&manager, adapter_address, device_address).await;
-            }
-            bluer::AdapterEvent::PropertyChanged(_property_change) => {}
+    let mut heartbeat_interval = interval(ADAPTER_HEARTBEAT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = heartbeat_interval.tick() => {},
+            event = stream.next() => {
+                match event {
+                    Some(DeviceEvent::Added(device_address)) => {
+                        handle_device_updated(&manager, adapter_address, adapter.clone(), device_address)
+                            .await;
+                    }
+                    Some(DeviceEvent::Removed(device_address)) => {
+                        handle_device_removed(&manager, adapter_address, device_address).await;
+                    }
+                    None => break,
+                }
+            },
         }
+        // Handling device events may hang on D-Bus, which the heartbeat then tells
+        *heartbeat.lock().unwrap() = Instant::now();
     }
 }
 
 async fn handle_device_updated(
     manager: &ITagSwarmManager,
     adapter_address: bluer::Address,
-    adapter: Arc<bluer::Adapter>,
+    adapter: Arc<dyn Adapter>,
     device_address: bluer::Address,
 ) -> () {
     let device = match adapter.device(device_address) {
@@ -203,28 +458,32 @@ async fn handle_device_removed(
 async fn on_device_discovered(
     manager: &ITagSwarmManager,
     adapter_address: bluer::Address,
-    adapter: Arc<bluer::Adapter>,
+    adapter: Arc<dyn Adapter>,
     device_address: bluer::Address,
-    device: bluer::Device,
+    device: Arc<dyn Device>,
 ) {
+    let device_config = manager.config.device_config(&device_address);
+    if !device_config.enabled {
+        return;
+    }
+
     // Check if the device is iTAG
-    match device.name().await {
-        Ok(Some(name)) => {
-            if name.to_ascii_uppercase().trim() != "ITAG" {
-                // Not iTag, ignore
-                return;
-            }
-        }
-        Ok(None) => {
-            // Not iTag, ignore
-            return;
-        }
+    let device_info = match DeviceInfo::read(&*device).await {
+        Ok(device_info) => device_info,
         Err(_error) => {
             // Device probably got lost. It might have been an iTag, so clear the state
             on_device_lost(manager, adapter_address, device_address).await;
             return;
         }
     };
+    let match_reason = match manager.config.device_matcher.matches(&device_info) {
+        Some(match_reason) => match_reason,
+        None => {
+            // Not iTag, ignore
+            debug!(device:% = device_address, adapter:% = adapter_address; "Device is not a tag");
+            return;
+        }
+    };
 
     // Check if the device is in range
     match device.rssi().await {
@@ -241,12 +500,36 @@ async fn on_device_discovered(
         }
     };
 
+    // Leave unknown tags alone, so that the neighbour's keyfinder is never connected
+    if manager.config.only_known_devices
+        && !is_known(&manager.config, &manager.inventory, &device_address)
+    {
+        let mut ignored = manager.ignored.lock().unwrap();
+        if ignored.insert(device_address, Instant::now()).is_none() {
+            info!(device:% = device_address; "Ignoring unknown tag");
+        }
+        return;
+    }
+
     // Find device monitor and inform it
     let mut actors = manager.actors.lock().unwrap();
     let actor = match actors.get(&device_address) {
         Some(actor) => actor.clone(),
         None => {
-            let actor = DeviceActor::new(&device_address, manager.mqttc.clone());
+            info!(
+                device:% = device_address, adapter:% = adapter_address;
+                "Device is a tag: {0}", match_reason
+            );
+            if let Some(name) = &device_config.name {
+                info!(device:% = device_address; "Device is {0}", name);
+            }
+            let forgotten = manager.forgotten.lock().unwrap().remove(&device_address);
+            let actor = DeviceActor::new(
+                &device_address,
+                device_config,
+                manager.mqttc.clone(),
+                forgotten,
+            );
             _ = actors.insert(device_address, actor.clone());
             actor
         }
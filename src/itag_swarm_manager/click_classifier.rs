@@ -0,0 +1,74 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use tokio::time::{Duration, Instant};
+
+/// Number of notifications within click window that is regarded as holding the button.
+/// iTags repeat the notification while the button is held down.
+const HOLD_COUNT: u32 = 4;
+
+pub enum ClickAction {
+    Single,
+    Double,
+    Triple,
+    Hold,
+}
+
+impl ClickAction {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            ClickAction::Single => "single",
+            ClickAction::Double => "double",
+            ClickAction::Triple => "triple",
+            ClickAction::Hold => "hold",
+        };
+    }
+}
+
+/// Groups button notifications into clicks. Notifications that follow each other
+/// within the click window belong to the same click sequence.
+pub struct ClickClassifier {
+    window: Duration,
+    count: u32,
+    deadline: Option<Instant>,
+}
+
+impl ClickClassifier {
+    pub fn new(window: Duration) -> ClickClassifier {
+        return ClickClassifier {
+            window: window,
+            count: 0,
+            deadline: None,
+        };
+    }
+
+    /// Registers a button notification. Hold is reported as soon as it is detected,
+    /// other actions only after the sequence ends.
+    pub fn press(&mut self, now: Instant) -> Option<ClickAction> {
+        self.count += 1;
+        self.deadline = Some(now + self.window);
+        if self.count == HOLD_COUNT {
+            return Some(ClickAction::Hold);
+        }
+        return None;
+    }
+
+    /// Time when the current sequence ends, if there is one.
+    pub fn deadline(&self) -> Option<Instant> {
+        return self.deadline;
+    }
+
+    /// Ends the current sequence. Called when the deadline has passed.
+    pub fn finish(&mut self) -> Option<ClickAction> {
+        let count = self.count;
+        self.count = 0;
+        self.deadline = None;
+        return match count {
+            1 => Some(ClickAction::Single),
+            2 => Some(ClickAction::Double),
+            3 => Some(ClickAction::Triple),
+            // Hold was already reported
+            _ => None,
+        };
+    }
+}
@@ -1,45 +1,115 @@
 // Author: Jarkko Pöyry
 // See LICENSE for License
 
+use crate::bluetooth::{
+    write_alert_level, Adapter, Characteristic, Device, EventStream, BATTERY_LEVEL_CHARACTERISTIC,
+    BATTERY_SERVICE, BUTTON_CHARACTERISTIC, BUTTON_SERVICE, IMMEDIATE_ALERT_SERVICE,
+    LINK_LOSS_SERVICE,
+};
+use crate::config::{AlertLevel, DeviceConfig};
+use crate::itag_swarm_manager::click_classifier::ClickClassifier;
+use crate::itag_swarm_manager::handover_tracker::HandoverTracker;
+use crate::itag_swarm_manager::reconnect_backoff::ReconnectBackoff;
+use crate::mqtt_client::DeviceContext;
 use crate::MqttClient;
-use bluer::Uuid;
+use log::{debug, info, warn};
+use serde_json::json;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio::time::{sleep, Duration};
-use tokio_stream::Stream;
+use std::sync::Mutex;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, timeout_at, Duration, Instant};
 use tokio_stream::StreamExt;
 
+/// How often RSSI of a connected tag is compared between adapters.
+const HANDOVER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long closing the connection for handover may take before it is given up.
+const HANDOVER_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct DeviceActor {
     device_address: bluer::Address,
     sender: mpsc::UnboundedSender<DeviceMessage>,
+    config: DeviceConfig,
     mqttc: Arc<MqttClient>,
+    is_present: AtomicBool,
+    is_battery_low: AtomicBool,
+    context: Mutex<DeviceContext>,
+    /// When the device was last seen, if it is currently neither visible nor connected.
+    absent_since: Mutex<Option<Instant>>,
+    /// When RSSI was last published. Limits publishing to once per `rssi_interval`.
+    last_rssi_publish: Mutex<Option<Instant>>,
 }
 
 enum DeviceMessage {
     Stabilized,
     DeviceDiscovered {
         adapter_address: bluer::Address,
-        device: bluer::Device,
+        device: Arc<dyn Device>,
     },
     DeviceLost {
         adapter_address: bluer::Address,
     },
-    ButtonMonitorExit,
+    AdapterRemoved {
+        adapter_address: bluer::Address,
+    },
+    ButtonMonitorConnected {
+        generation: u64,
+        adapter_address: bluer::Address,
+        device: Arc<dyn Device>,
+    },
+    ButtonMonitorExit {
+        generation: u64,
+        error: Option<String>,
+    },
+    RetryConnect,
+    Alert {
+        payload: String,
+    },
+    AlertTimeout {
+        generation: u64,
+    },
+    RssiTick,
+    HandoverTick,
+    HandoverDisconnected {
+        generation: u64,
+        error: Option<String>,
+    },
+    HandoverTimeout {
+        generation: u64,
+    },
+    Forget {
+        done: oneshot::Sender<()>,
+    },
+    Shutdown {
+        done: oneshot::Sender<()>,
+    },
 }
 
 impl DeviceActor {
-    pub fn new(device_address: &bluer::Address, mqttc: Arc<MqttClient>) -> Arc<DeviceActor> {
+    pub fn new(
+        device_address: &bluer::Address,
+        config: DeviceConfig,
+        mqttc: Arc<MqttClient>,
+        forgotten: Option<oneshot::Receiver<()>>,
+    ) -> Arc<DeviceActor> {
         let (sender, receiver) = mpsc::unbounded_channel();
         let device = Arc::new(DeviceActor {
             device_address: device_address.clone(),
             sender: sender,
+            config: config,
             mqttc: mqttc,
+            is_present: AtomicBool::new(false),
+            is_battery_low: AtomicBool::new(false),
+            context: Mutex::new(DeviceContext::default()),
+            absent_since: Mutex::new(None),
+            last_rssi_publish: Mutex::new(None),
         });
 
         // Create device monitor for it
         let device_copy = device.clone();
-        tokio::spawn(async move { device_manager_loop(device_copy, receiver).await });
+        tokio::spawn(async move { device_manager_loop(device_copy, receiver, forgotten).await });
 
         return device;
     }
@@ -47,8 +117,8 @@ impl DeviceActor {
     pub fn device_discovered(
         &self,
         adapter_address: bluer::Address,
-        _adapter: Arc<bluer::Adapter>,
-        device: bluer::Device,
+        _adapter: Arc<dyn Adapter>,
+        device: Arc<dyn Device>,
     ) {
         self.send(DeviceMessage::DeviceDiscovered {
             adapter_address,
@@ -60,27 +130,158 @@ impl DeviceActor {
         self.send(DeviceMessage::DeviceLost { adapter_address });
     }
 
+    /// Drops everything seen through the adapter, including a connection through it.
+    pub fn adapter_removed(&self, adapter_address: bluer::Address) {
+        self.send(DeviceMessage::AdapterRemoved { adapter_address });
+    }
+
+    /// Sets the alert level of the connected tag. See `parse_alert_command` for the format.
+    pub fn alert(&self, payload: String) {
+        self.send(DeviceMessage::Alert { payload });
+    }
+
+    /// Stops managing the device, and removes it from Home Assistant. Its retained
+    /// topics are cleared if so configured. The receiver completes when done.
+    pub fn forget(&self) -> oneshot::Receiver<()> {
+        let (done, receiver) = oneshot::channel();
+        self.send(DeviceMessage::Forget { done });
+        return receiver;
+    }
+
+    /// Disconnects the tag and stops managing it. The receiver completes when done.
+    pub fn shutdown(&self) -> oneshot::Receiver<()> {
+        let (done, receiver) = oneshot::channel();
+        self.send(DeviceMessage::Shutdown { done });
+        return receiver;
+    }
+
+    /// Whether the device has been gone for longer than `expire_after`.
+    pub fn is_expired(&self) -> bool {
+        let expire_after = match self.config.expire_after {
+            Some(expire_after) => expire_after,
+            None => return false,
+        };
+        return match *self.absent_since.lock().unwrap() {
+            Some(absent_since) => absent_since.elapsed() >= expire_after,
+            None => false,
+        };
+    }
+
+    pub async fn publish_discovery(&self) {
+        self.mqttc.publish_discovery(&self.device_address).await;
+    }
+
+    /// Publishes the current state of the device. Button is always published released.
+    pub fn publish_state(&self) {
+        let is_present = self.is_present.load(Ordering::Relaxed);
+        self.mqttc.publish_device(
+            &self.device_address,
+            &self.context(),
+            false,
+            is_present,
+            false,
+        );
+    }
+
+    /// Publishes the current state of the device like `publish_state`, but waits for
+    /// room in the MQTT queue instead of discarding.
+    pub async fn announce_state(&self) {
+        let is_present = self.is_present.load(Ordering::Relaxed);
+        self.mqttc
+            .announce_device(&self.device_address, &self.context(), false, is_present)
+            .await;
+    }
+
+    pub fn is_present(&self) -> bool {
+        return self.is_present.load(Ordering::Relaxed);
+    }
+
+    fn set_present(&self, is_present: bool) {
+        self.is_present.store(is_present, Ordering::Relaxed);
+        self.publish_state();
+    }
+
+    fn set_battery_level(&self, level: u8) {
+        self.mqttc
+            .publish_battery(&self.device_address, &self.context(), level);
+
+        // Fire the event only when crossing the threshold
+        let is_low = level < self.config.battery_low_threshold;
+        if self.is_battery_low.swap(is_low, Ordering::Relaxed) != is_low && is_low {
+            info!(device:% = self.device_address; "Battery low: {0}%", level);
+            self.mqttc
+                .publish_event(&self.device_address, &self.context(), "battery_low");
+        }
+    }
+
+    fn context(&self) -> DeviceContext {
+        return self.context.lock().unwrap().clone();
+    }
+
+    fn set_adapter(&self, adapter: Option<String>) {
+        self.context.lock().unwrap().adapter = adapter;
+    }
+
+    fn set_rssi(&self, rssi: Option<i16>) {
+        self.context.lock().unwrap().rssi = rssi;
+    }
+
     fn send(&self, message: DeviceMessage) {
-        self.sender.send(message).unwrap();
+        // Fails only after the device has been forgotten, when nobody cares
+        let _ = self.sender.send(message);
     }
 }
 
 struct ConnectedAdapter {
-    device: Arc<bluer::Device>,
+    device: Arc<dyn Device>,
+}
+
+/// Connection closed to move the tag to a better adapter.
+struct Handover {
+    /// Name of the adapter the tag is being moved away from.
+    from: String,
+    /// Monitor of the connection being closed.
+    generation: u64,
+}
+
+/// Task that connects to the tag and monitors the connection.
+struct ButtonMonitor {
+    adapter_address: bluer::Address,
+    device: Arc<dyn Device>,
+    /// Messages of earlier, torn down monitors are ignored.
+    generation: u64,
+    task: JoinHandle<()>,
 }
 
 async fn device_manager_loop(
     actor: Arc<DeviceActor>,
     mut receiver: mpsc::UnboundedReceiver<DeviceMessage>,
+    forgotten: Option<oneshot::Receiver<()>>,
 ) {
     let mut discovered_on_adapter: HashMap<bluer::Address, ConnectedAdapter> = HashMap::new();
     let mut stabilized: bool = false;
-    let mut has_button_monitor: bool = false;
+    let mut button_monitor: Option<ButtonMonitor> = None;
+    let mut monitor_generation: u64 = 0;
+    let mut backoff = ReconnectBackoff::new(&actor.config);
+    let mut connected_device: Option<Arc<dyn Device>> = None;
+    let mut connected_adapter: Option<bluer::Address> = None;
+    let mut handover = HandoverTracker::new(&actor.config);
+    let mut handover_from: Option<Handover> = None;
+    let mut alert_generation: u64 = 0;
+
+    // Previous actor of the device must not clear the topics after they are announced
+    if let Some(forgotten) = forgotten {
+        let _ = forgotten.await;
+    }
 
-    // When device is seen, publish it on MQTT as retained but without it being present
+    // Announce the device to Home Assistant, and publish it on MQTT as retained but
+    // without it being present. Awaited, so that several tags found at once do not
+    // overflow the MQTT queue.
+    actor.publish_discovery().await;
     actor
         .mqttc
-        .publish_device(&actor.device_address, true, false, false);
+        .announce_device(&actor.device_address, &actor.context(), true, false)
+        .await;
 
     // Upon first discovery, we wait a second to make sure all adapters have stabilized
     {
@@ -91,6 +292,42 @@ async fn device_manager_loop(
         });
     }
 
+    // Refresh RSSI telemetry periodically, also while connected. Ticks when the next
+    // publish is due, so that readings published on connect do not skip a tick.
+    {
+        let actor = actor.clone();
+        tokio::spawn(async move {
+            let mut due = Instant::now() + actor.config.rssi_interval;
+            loop {
+                sleep_until(due).await;
+                let last_publish = *actor.last_rssi_publish.lock().unwrap();
+                if let Some(last_publish) = last_publish {
+                    if last_publish + actor.config.rssi_interval > Instant::now() {
+                        due = last_publish + actor.config.rssi_interval;
+                        continue;
+                    }
+                }
+                if actor.sender.send(DeviceMessage::RssiTick).is_err() {
+                    break;
+                }
+                due = Instant::now() + actor.config.rssi_interval;
+            }
+        });
+    }
+
+    // Keep comparing adapters while connected, to follow the tag around
+    if handover.is_some() {
+        let actor = actor.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(HANDOVER_CHECK_INTERVAL).await;
+                if actor.sender.send(DeviceMessage::HandoverTick).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
     while let Some(event) = receiver.recv().await {
         match event {
             DeviceMessage::Stabilized {} => {
@@ -100,28 +337,306 @@ async fn device_manager_loop(
                 adapter_address,
                 device,
             } => {
-                let discovered = ConnectedAdapter {
-                    device: Arc::new(device),
-                };
+                let discovered = ConnectedAdapter { device: device };
                 if let None = discovered_on_adapter.insert(adapter_address, discovered) {
-                    println!(
-                        "Discovered {0} on {1}",
-                        actor.device_address, adapter_address
+                    info!(
+                        device:% = actor.device_address, adapter:% = adapter_address;
+                        "Discovered"
                     );
                 }
             }
             DeviceMessage::DeviceLost { adapter_address } => {
                 if let Some(_) = discovered_on_adapter.remove(&adapter_address) {
                     if discovered_on_adapter.is_empty() {
-                        println!(
-                            "Device {0} no longer visible on any adapter",
-                            actor.device_address
+                        info!(
+                            device:% = actor.device_address, adapter:% = adapter_address;
+                            "No longer visible on any adapter"
                         );
                     }
                 }
             }
-            DeviceMessage::ButtonMonitorExit {} => {
-                has_button_monitor = false;
+            DeviceMessage::AdapterRemoved { adapter_address } => {
+                if let Some(_) = discovered_on_adapter.remove(&adapter_address) {
+                    info!(
+                        device:% = actor.device_address, adapter:% = adapter_address;
+                        "Lost adapter"
+                    );
+                }
+
+                // Do not wait for BlueZ to notice that the connection is gone
+                let is_connected_through = match &button_monitor {
+                    Some(monitor) => monitor.adapter_address == adapter_address,
+                    None => false,
+                };
+                if is_connected_through {
+                    if let Some(monitor) = button_monitor.take() {
+                        monitor.task.abort();
+                    }
+                    connected_device = None;
+                    connected_adapter = None;
+                    if let Some(handover) = &mut handover {
+                        handover.reset();
+                    }
+                    handover_from = None;
+                    handle_disconnect(&actor, &mut backoff, Some("adapter removed".to_string()));
+                }
+            }
+            DeviceMessage::ButtonMonitorConnected {
+                generation,
+                adapter_address,
+                device,
+            } => {
+                if !is_current_monitor(&button_monitor, generation) {
+                    continue;
+                }
+                if let Some(from) = handover_from.take() {
+                    if from.from != device.adapter_name() {
+                        info!(
+                            device:% = actor.device_address, adapter:% = adapter_address;
+                            "Handed over from {0} to {1}", from.from, device.adapter_name()
+                        );
+                        actor.mqttc.publish_event(
+                            &actor.device_address,
+                            &actor.context(),
+                            "handover",
+                        );
+                    }
+                }
+                connected_device = Some(device);
+                connected_adapter = Some(adapter_address);
+                backoff.connected(Instant::now());
+                if backoff.failures() > 0 {
+                    actor.mqttc.publish_diagnostics(
+                        &actor.device_address,
+                        json!({
+                            "connected": true,
+                            "failures": backoff.failures(),
+                            "retry_in": null,
+                            "last_error": null,
+                        }),
+                    );
+                }
+            }
+            DeviceMessage::ButtonMonitorExit { generation, error } => {
+                if !is_current_monitor(&button_monitor, generation) {
+                    continue;
+                }
+                button_monitor = None;
+                connected_device = None;
+                connected_adapter = None;
+                if let Some(handover) = &mut handover {
+                    handover.reset();
+                }
+
+                // Connection was closed for handover, reconnect right away and stay present
+                let is_handover = match &handover_from {
+                    Some(from) => from.generation == generation,
+                    None => false,
+                };
+                if is_handover && error.is_none() {
+                    actor.set_adapter(None);
+                } else {
+                    handover_from = None;
+                    handle_disconnect(&actor, &mut backoff, error);
+                }
+            }
+            DeviceMessage::RetryConnect => {}
+            DeviceMessage::Alert { payload } => {
+                let (level, mut duration) = match parse_alert_command(&payload) {
+                    Ok(command) => command,
+                    Err(err) => {
+                        actor
+                            .mqttc
+                            .publish_alert_result(&actor.device_address, Err(err));
+                        continue;
+                    }
+                };
+                let device = match &connected_device {
+                    Some(device) => device.clone(),
+                    None => {
+                        actor.mqttc.publish_alert_result(
+                            &actor.device_address,
+                            Err("not connected".to_string()),
+                        );
+                        continue;
+                    }
+                };
+
+                if level != AlertLevel::Off && duration.is_none() {
+                    duration = actor.config.alert_duration;
+                }
+
+                // Newer command supersedes the timeout of the previous one
+                alert_generation += 1;
+                let generation = alert_generation;
+                let actor = actor.clone();
+                tokio::spawn(async move {
+                    let result = write_alert_level(&*device, IMMEDIATE_ALERT_SERVICE, level)
+                        .await
+                        .map_err(|err| err.message);
+                    let is_ok = result.is_ok();
+                    actor
+                        .mqttc
+                        .publish_alert_result(&actor.device_address, result);
+
+                    if let (true, Some(duration)) = (is_ok, duration) {
+                        sleep(duration).await;
+                        actor.send(DeviceMessage::AlertTimeout { generation });
+                    }
+                });
+            }
+            DeviceMessage::AlertTimeout { generation } => {
+                if generation == alert_generation {
+                    if let Some(device) = &connected_device {
+                        let device = device.clone();
+                        tokio::spawn(async move {
+                            _ = write_alert_level(
+                                &*device,
+                                IMMEDIATE_ALERT_SERVICE,
+                                AlertLevel::Off,
+                            )
+                            .await;
+                        });
+                    }
+                }
+            }
+            DeviceMessage::RssiTick => {
+                let rssi = read_rssi(&discovered_on_adapter).await;
+                publish_rssi(&actor, &discovered_on_adapter, &rssi);
+            }
+            DeviceMessage::HandoverTick => {
+                let (Some(handover), Some(device), Some(current), Some(monitor)) = (
+                    &mut handover,
+                    &connected_device,
+                    connected_adapter,
+                    &button_monitor,
+                ) else {
+                    continue;
+                };
+                if handover_from.is_some() || discovered_on_adapter.len() < 2 {
+                    continue;
+                }
+                let rssi = read_rssi(&discovered_on_adapter).await;
+                if let Some(target) = handover.update(current, &rssi, Instant::now()) {
+                    if let Some(target) = discovered_on_adapter.get(&target) {
+                        info!(
+                            device:% = actor.device_address, adapter:% = current;
+                            "Handing over from {0} to {1}",
+                            device.adapter_name(),
+                            target.device.adapter_name()
+                        );
+                    }
+                    // The monitor exits when the connection closes, and the tag is then
+                    // connected on the best adapter
+                    let generation = monitor.generation;
+                    handover_from = Some(Handover {
+                        from: device.adapter_name().to_string(),
+                        generation: generation,
+                    });
+                    let actor = actor.clone();
+                    let device = device.clone();
+                    tokio::spawn(async move {
+                        let deadline = Instant::now() + HANDOVER_DISCONNECT_TIMEOUT;
+                        let error = match timeout_at(deadline, device.disconnect()).await {
+                            Ok(Ok(())) => None,
+                            Ok(Err(err)) => Some(err.to_string()),
+                            Err(_elapsed) => Some("timeout".to_string()),
+                        };
+                        let is_disconnected = error.is_none();
+                        actor.send(DeviceMessage::HandoverDisconnected { generation, error });
+
+                        // The monitor should exit by now, give up on it if it has not
+                        if is_disconnected {
+                            sleep_until(deadline).await;
+                            actor.send(DeviceMessage::HandoverTimeout { generation });
+                        }
+                    });
+                }
+            }
+            DeviceMessage::HandoverDisconnected { generation, error } => {
+                // On failure, stay on the current adapter until the dwell time has
+                // passed again
+                let Some(error) = error else {
+                    continue;
+                };
+                if let Some(from) = &handover_from {
+                    if from.generation == generation {
+                        warn!(
+                            device:% = actor.device_address;
+                            "Cannot disconnect for handover: {0}", error
+                        );
+                        handover_from = None;
+                        if let Some(handover) = &mut handover {
+                            handover.reset();
+                        }
+                    }
+                }
+            }
+            DeviceMessage::HandoverTimeout { generation } => {
+                let is_handover = match &handover_from {
+                    Some(from) => from.generation == generation,
+                    None => false,
+                };
+                if !is_handover || !is_current_monitor(&button_monitor, generation) {
+                    continue;
+                }
+                warn!(
+                    device:% = actor.device_address;
+                    "Connection did not close for handover, reconnecting"
+                );
+                if let Some(monitor) = button_monitor.take() {
+                    monitor.task.abort();
+                }
+                connected_device = None;
+                connected_adapter = None;
+                if let Some(handover) = &mut handover {
+                    handover.reset();
+                }
+                handover_from = None;
+                actor.set_adapter(None);
+            }
+            DeviceMessage::Forget { done } => {
+                info!(device:% = actor.device_address; "Forgetting device");
+                if let Some(monitor) = button_monitor.take() {
+                    monitor.task.abort();
+                }
+                actor.mqttc.remove_discovery(&actor.device_address).await;
+                if actor.config.expire_clear_retained {
+                    actor.mqttc.clear_retained(&actor.device_address).await;
+                }
+                let _ = done.send(());
+                return;
+            }
+            DeviceMessage::Shutdown { done } => {
+                if let Some(monitor) = button_monitor.take() {
+                    monitor.task.abort();
+                    if let (true, Some(device)) =
+                        (actor.config.silence_on_shutdown, &connected_device)
+                    {
+                        _ = write_alert_level(&**device, LINK_LOSS_SERVICE, AlertLevel::Off).await;
+                        _ = write_alert_level(&**device, IMMEDIATE_ALERT_SERVICE, AlertLevel::Off)
+                            .await;
+                    }
+                    if let Err(err) = monitor.device.disconnect().await {
+                        warn!(device:% = actor.device_address; "Cannot disconnect: {0}", err);
+                    }
+                }
+                // \note: not retained. Awaited, as every tag goes absent at once.
+                if actor.is_present.swap(false, Ordering::Relaxed) {
+                    actor.announce_state().await;
+                }
+                let _ = done.send(());
+                return;
+            }
+        }
+
+        // Start the expiry clock when the device is neither visible nor connected
+        {
+            let mut absent_since = actor.absent_since.lock().unwrap();
+            if !discovered_on_adapter.is_empty() || button_monitor.is_some() {
+                *absent_since = None;
+            } else if absent_since.is_none() {
+                *absent_since = Some(Instant::now());
             }
         }
 
@@ -130,48 +645,160 @@ async fn device_manager_loop(
         }
 
         // Connect to the device on the best adapter
-        if !has_button_monitor {
-            if let Some(adapter) = get_best_adapter(&discovered_on_adapter).await {
-                has_button_monitor = true;
-
+        if button_monitor.is_none() && backoff.can_attempt(Instant::now()) {
+            let rssi = read_rssi(&discovered_on_adapter).await;
+            publish_rssi(&actor, &discovered_on_adapter, &rssi);
+
+            if let Some((adapter_address, adapter)) =
+                get_best_adapter(&discovered_on_adapter, &rssi)
+            {
+                monitor_generation += 1;
+                let generation = monitor_generation;
                 let actor = actor.clone();
                 let device = adapter.device.clone();
-                let mqttc = actor.mqttc.clone();
-                tokio::spawn(async move {
-                    _ = monitor_itag_button(&device, mqttc).await;
-                    actor.send(DeviceMessage::ButtonMonitorExit {})
+                let task =
+                    tokio::spawn(async move {
+                        let error =
+                            match monitor_itag_button(&actor, adapter_address, generation, device)
+                                .await
+                            {
+                                Ok(()) => None,
+                                Err(err) => Some(err.to_string()),
+                            };
+                        actor.send(DeviceMessage::ButtonMonitorExit { generation, error })
+                    });
+                button_monitor = Some(ButtonMonitor {
+                    adapter_address: adapter_address,
+                    device: adapter.device.clone(),
+                    generation: generation,
+                    task: task,
                 });
             }
         }
     }
 }
 
-async fn get_best_adapter(
+/// Reads the current RSSI of the device on every adapter that sees it.
+async fn read_rssi(
     adapters: &HashMap<bluer::Address, ConnectedAdapter>,
-) -> Option<&ConnectedAdapter> {
-    let mut best_candidate: Option<(i16, &ConnectedAdapter)> = None;
-    for (_, adapter) in adapters.iter() {
+) -> HashMap<bluer::Address, i16> {
+    let mut rssi: HashMap<bluer::Address, i16> = HashMap::new();
+    for (adapter_address, adapter) in adapters.iter() {
         if let Ok(Some(this_rssi)) = adapter.device.rssi().await {
-            if let Some((best_rssi, _)) = best_candidate {
+            rssi.insert(*adapter_address, this_rssi);
+        }
+    }
+    return rssi;
+}
+
+/// Publishes RSSI readings, but at most once per configured interval.
+fn publish_rssi(
+    actor: &DeviceActor,
+    adapters: &HashMap<bluer::Address, ConnectedAdapter>,
+    rssi: &HashMap<bluer::Address, i16>,
+) {
+    if rssi.is_empty() {
+        return;
+    }
+    actor.set_rssi(rssi.values().max().copied());
+    {
+        let mut last_publish = actor.last_rssi_publish.lock().unwrap();
+        if let Some(last_publish) = *last_publish {
+            if last_publish.elapsed() < actor.config.rssi_interval {
+                return;
+            }
+        }
+        *last_publish = Some(Instant::now());
+    }
+
+    let mut readings: Vec<(String, i16)> = Vec::new();
+    for (adapter_address, this_rssi) in rssi.iter() {
+        if let Some(adapter) = adapters.get(adapter_address) {
+            readings.push((adapter.device.adapter_name().to_string(), *this_rssi));
+        }
+    }
+    actor
+        .mqttc
+        .publish_rssi(&actor.device_address, &actor.context(), &readings);
+}
+
+fn get_best_adapter<'a>(
+    adapters: &'a HashMap<bluer::Address, ConnectedAdapter>,
+    rssi: &HashMap<bluer::Address, i16>,
+) -> Option<(bluer::Address, &'a ConnectedAdapter)> {
+    let mut best_candidate: Option<(i16, bluer::Address, &ConnectedAdapter)> = None;
+    for (adapter_address, adapter) in adapters.iter() {
+        if let Some(&this_rssi) = rssi.get(adapter_address) {
+            if let Some((best_rssi, _, _)) = best_candidate {
                 if this_rssi > best_rssi {
-                    best_candidate = Some((this_rssi, adapter));
+                    best_candidate = Some((this_rssi, *adapter_address, adapter));
                 }
             } else {
-                best_candidate = Some((this_rssi, adapter));
+                best_candidate = Some((this_rssi, *adapter_address, adapter));
             }
         }
     }
 
     match best_candidate {
-        Some((_, adapter)) => Some(adapter),
+        Some((_, adapter_address, adapter)) => Some((adapter_address, adapter)),
         None => None,
     }
 }
 
-async fn monitor_itag_button(device: &bluer::Device, mqttc: Arc<MqttClient>) -> Result<(), bluer::Error> {
-    // Connect. Connections more than 5 seconds are unlikely to succeed so abort.
+fn is_current_monitor(button_monitor: &Option<ButtonMonitor>, generation: u64) -> bool {
+    return match button_monitor {
+        Some(monitor) => monitor.generation == generation,
+        None => false,
+    };
+}
+
+/// Marks the device absent after its connection ended, and schedules the next
+/// connection attempt.
+fn handle_disconnect(
+    actor: &Arc<DeviceActor>,
+    backoff: &mut ReconnectBackoff,
+    error: Option<String>,
+) {
+    // \note: not retained
+    if actor.is_present.load(Ordering::Relaxed) {
+        actor.set_present(false);
+    }
+    actor.set_adapter(None);
+
+    // Retry later instead of on the very next message
+    let delay = backoff.disconnected(Instant::now());
+    if let Some(error) = &error {
+        warn!(
+            device:% = actor.device_address;
+            "Connection failed, retrying in {0:.1}s: {1}", delay.as_secs_f64(), error
+        );
+    }
+    actor.mqttc.publish_diagnostics(
+        &actor.device_address,
+        json!({
+            "connected": false,
+            "failures": backoff.failures(),
+            "retry_in": delay.as_secs_f64(),
+            "last_error": error,
+        }),
+    );
+    let actor = actor.clone();
+    tokio::spawn(async move {
+        sleep(delay).await;
+        let _ = actor.sender.send(DeviceMessage::RetryConnect);
+    });
+}
+
+async fn monitor_itag_button(
+    actor: &DeviceActor,
+    adapter_address: bluer::Address,
+    generation: u64,
+    device: Arc<dyn Device>,
+) -> Result<(), bluer::Error> {
+    // Connect. Long connection attempts are unlikely to succeed so abort.
     if !device.is_connected().await? {
-        let timeout = sleep(Duration::from_secs(5));
+        debug!(device:% = actor.device_address, adapter:% = adapter_address; "Connecting");
+        let timeout = sleep(actor.config.connect_timeout);
         tokio::pin!(timeout);
         tokio::select! {
             connect_result = device.connect() => {
@@ -192,23 +819,80 @@ async fn monitor_itag_button(device: &bluer::Device, mqttc: Arc<MqttClient>) ->
     }
 
     let events = device.events().await?;
-    let button_notify = get_button_notify_stream(device).await?;
+    let button_notify = get_button_notify_stream(&*device).await?;
 
     // On connect, the itag beeps. Send manual alert to override the auto-alert.
-    _ = stop_beeping(device).await;
+    let has_immediate_alert = write_alert_level(&*device, IMMEDIATE_ALERT_SERVICE, AlertLevel::Off)
+        .await
+        .is_ok();
+
+    // Configure what the tag does when the connection is lost, if it lets us
+    let has_link_loss_alert =
+        match write_alert_level(&*device, LINK_LOSS_SERVICE, actor.config.link_loss_alert).await {
+            Ok(()) => true,
+            Err(err) => {
+                warn!(
+                    device:% = actor.device_address, adapter:% = adapter_address;
+                    "Cannot set link loss alert level: {0}", err
+                );
+                false
+            }
+        };
+    let has_battery = match device
+        .characteristic(BATTERY_SERVICE, BATTERY_LEVEL_CHARACTERISTIC)
+        .await
+    {
+        Ok(char) => char.is_some(),
+        Err(_) => false,
+    };
+    actor.mqttc.publish_capabilities(
+        &actor.device_address,
+        &[
+            ("immediate_alert", has_immediate_alert),
+            ("link_loss_alert", has_link_loss_alert),
+            ("battery", has_battery),
+        ],
+    );
+
+    info!(device:% = actor.device_address, adapter:% = adapter_address; "Connected");
+    actor.set_adapter(Some(device.adapter_name().to_string()));
+    actor.send(DeviceMessage::ButtonMonitorConnected {
+        generation: generation,
+        adapter_address: adapter_address,
+        device: device.clone(),
+    });
 
     // Mark as present
     // \note: not retained
-    mqttc.publish_device(&device.address(), false, true, false);
+    actor.set_present(true);
+
+    // Battery is optional, so its failure does not end the monitor
+    let battery_monitor = monitor_battery(actor, &*device);
+    let mut has_battery_monitor = true;
+
+    let mut click_classifier = ClickClassifier::new(actor.config.click_window);
 
     tokio::pin!(events);
     tokio::pin!(button_notify);
+    tokio::pin!(battery_monitor);
     loop {
         tokio::select! {
+            battery_result = &mut battery_monitor, if has_battery_monitor => {
+                has_battery_monitor = false;
+                if let Err(err) = battery_result {
+                    warn!(
+                        device:% = actor.device_address, adapter:% = adapter_address;
+                        "Cannot monitor battery: {0}", err
+                    );
+                }
+            },
             event_maybe = events.next() => {
                 match event_maybe {
-                    Some(_event) => {
-                        // println!("On received event {:?}", event);
+                    Some(event) => {
+                        debug!(
+                            device:% = actor.device_address, adapter:% = adapter_address;
+                            "Received event {0}", event
+                        );
                     },
                     None => {
                         break;
@@ -219,45 +903,44 @@ async fn monitor_itag_button(device: &bluer::Device, mqttc: Arc<MqttClient>) ->
                 match event_maybe {
                     Some(event) => {
                         // Received button. Flip button.
-                        println!("On received button {:?}", event);
-                        mqttc.publish_device(&device.address(), false, true, true);
-                        mqttc.publish_device(&device.address(), false, true, false);
+                        debug!(
+                            device:% = actor.device_address, adapter:% = adapter_address;
+                            "Received button {0:?}", event
+                        );
+                        let context = actor.context();
+                        actor.mqttc.publish_device(&actor.device_address, &context, false, true, true);
+                        actor.mqttc.publish_device(&actor.device_address, &context, false, true, false);
+
+                        if let Some(action) = click_classifier.press(Instant::now()) {
+                            actor.mqttc.publish_button_action(&actor.device_address, &context, action.as_str());
+                        }
                     },
                     None => {
                         break;
                     }
                 }
             },
+            _ = sleep_until(click_classifier.deadline().unwrap_or_else(Instant::now)),
+                if click_classifier.deadline().is_some() => {
+                if let Some(action) = click_classifier.finish() {
+                    actor.mqttc.publish_button_action(&actor.device_address, &actor.context(), action.as_str());
+                }
+            },
         }
     }
 
-    // Mark as absent
-    // \note: not retained
-    mqttc.publish_device(&device.address(), false, false, false);
-
+    // Marked absent by the actor, unless the connection moves to another adapter
     Ok(())
 }
 
-static BUTTON_SERVICE: Uuid = Uuid::from_u128(0x0000ffe0_0000_1000_8000_00805f9b34fb);
-static BUTTON_CHARACTERISTIC: Uuid = Uuid::from_u128(0x0000ffe1_0000_1000_8000_00805f9b34fb);
-
 async fn get_button_notify_stream(
-    device: &bluer::Device,
-) -> Result<impl Stream<Item = Vec<u8>>, bluer::Error> {
-    for service in device.services().await? {
-        let uuid = service.uuid().await?;
-        if uuid != BUTTON_SERVICE {
-            continue;
-        }
-
-        for char in service.characteristics().await? {
-            let uuid = char.uuid().await?;
-            if uuid != BUTTON_CHARACTERISTIC {
-                continue;
-            }
-
-            return char.notify().await;
-        }
+    device: &dyn Device,
+) -> Result<EventStream<Vec<u8>>, bluer::Error> {
+    if let Some(char) = device
+        .characteristic(BUTTON_SERVICE, BUTTON_CHARACTERISTIC)
+        .await?
+    {
+        return char.notify().await;
     }
     Err(bluer::Error {
         kind: bluer::ErrorKind::DoesNotExist,
@@ -265,24 +948,60 @@ async fn get_button_notify_stream(
     })
 }
 
-static IMMEDIATE_ALERT_SERVICE: Uuid = Uuid::from_u128(0x00001802_0000_1000_8000_00805f9b34fb);
-static ALERT_LEVEL_CHARACTERISTIC: Uuid = Uuid::from_u128(0x00002a06_0000_1000_8000_00805f9b34fb);
+/// Parses alert command of form `<off|mild|high> [duration in seconds]`.
+fn parse_alert_command(payload: &str) -> Result<(AlertLevel, Option<Duration>), String> {
+    let mut parts = payload.split_whitespace();
+    let level = match parts.next().and_then(AlertLevel::parse) {
+        Some(level) => level,
+        None => return Err(format!("invalid alert level in '{}'", payload)),
+    };
+    let duration = match parts.next() {
+        Some(duration) => match duration.parse::<u64>() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => return Err(format!("invalid alert duration in '{}'", payload)),
+        },
+        None => None,
+    };
+    if parts.next().is_some() {
+        return Err(format!("unexpected arguments in '{}'", payload));
+    }
+    if level == AlertLevel::Off && duration.is_some() {
+        return Err("duration is not allowed with 'off'".to_string());
+    }
+    return Ok((level, duration));
+}
+
+/// Publishes battery level on connect and then whenever it changes. Tags that do
+/// not support notifications are polled.
+async fn monitor_battery(actor: &DeviceActor, device: &dyn Device) -> Result<(), bluer::Error> {
+    let char: Box<dyn Characteristic> = device
+        .characteristic(BATTERY_SERVICE, BATTERY_LEVEL_CHARACTERISTIC)
+        .await?
+        .ok_or(bluer::Error {
+            kind: bluer::ErrorKind::DoesNotExist,
+            message: String::from("No battery level characteristic"),
+        })?;
+
+    let level = char.read().await?;
+    if let Some(level) = level.first() {
+        actor.set_battery_level(*level);
+    }
 
-async fn stop_beeping(device: &bluer::Device) -> Result<(), bluer::Error> {
-    for service in device.services().await? {
-        let uuid = service.uuid().await?;
-        if uuid != IMMEDIATE_ALERT_SERVICE {
-            continue;
+    if char.can_notify().await? {
+        let notify = char.notify().await?;
+        tokio::pin!(notify);
+        while let Some(level) = notify.next().await {
+            if let Some(level) = level.first() {
+                actor.set_battery_level(*level);
+            }
         }
-
-        for char in service.characteristics().await? {
-            let uuid = char.uuid().await?;
-            if uuid != ALERT_LEVEL_CHARACTERISTIC {
-                continue;
+    } else {
+        loop {
+            sleep(actor.config.battery_poll_interval).await;
+            let level = char.read().await?;
+            if let Some(level) = level.first() {
+                actor.set_battery_level(*level);
             }
-
-            let data = vec![0x0];
-            char.write(&data).await?
         }
     }
     Ok(())
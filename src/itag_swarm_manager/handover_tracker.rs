@@ -0,0 +1,83 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::config::DeviceConfig;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+/// Decides when a connected tag should move to another adapter. An adapter must see
+/// the tag stronger than the current one by the margin for the whole dwell time, so
+/// that momentary RSSI spikes do not make the connection bounce between adapters.
+pub struct HandoverTracker {
+    margin: i16,
+    dwell: Duration,
+    /// Adapter that has been beating the current one, and since when.
+    candidate: Option<(bluer::Address, Instant)>,
+}
+
+impl HandoverTracker {
+    /// Returns None if handover is disabled.
+    pub fn new(config: &DeviceConfig) -> Option<HandoverTracker> {
+        let margin = config.handover_margin?;
+        return Some(HandoverTracker {
+            margin: margin,
+            dwell: config.handover_dwell,
+            candidate: None,
+        });
+    }
+
+    /// Evaluates the latest RSSI readings of all adapters. Returns the adapter to hand
+    /// over to once one has beaten the current adapter for long enough.
+    pub fn update(
+        &mut self,
+        current: bluer::Address,
+        rssi: &HashMap<bluer::Address, i16>,
+        now: Instant,
+    ) -> Option<bluer::Address> {
+        // Without a reading of the current adapter there is nothing to compare against
+        let current_rssi = match rssi.get(&current) {
+            Some(current_rssi) => *current_rssi,
+            None => {
+                self.candidate = None;
+                return None;
+            }
+        };
+
+        let mut best: Option<(bluer::Address, i16)> = None;
+        for (adapter_address, this_rssi) in rssi.iter() {
+            if *adapter_address == current || *this_rssi < current_rssi.saturating_add(self.margin)
+            {
+                continue;
+            }
+            match best {
+                Some((_, best_rssi)) if best_rssi >= *this_rssi => {}
+                _ => best = Some((*adapter_address, *this_rssi)),
+            }
+        }
+        let target = match best {
+            Some((target, _)) => target,
+            None => {
+                self.candidate = None;
+                return None;
+            }
+        };
+
+        let since = match self.candidate {
+            Some((candidate, since)) if candidate == target => since,
+            _ => {
+                self.candidate = Some((target, now));
+                now
+            }
+        };
+        if now.duration_since(since) < self.dwell {
+            return None;
+        }
+        self.candidate = None;
+        return Some(target);
+    }
+
+    /// Forgets the candidate, e.g. when the connection is lost.
+    pub fn reset(&mut self) {
+        self.candidate = None;
+    }
+}
@@ -0,0 +1,80 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::config::DeviceConfig;
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+
+/// Delays reconnection after failed connection attempts. Delay doubles on every
+/// consecutive failure up to the maximum, and is randomized by the jitter so that
+/// tags dropped at the same time do not retry in lockstep. A connection that stays
+/// up long enough resets the delay.
+pub struct ReconnectBackoff {
+    min_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    reset_after: Duration,
+    failures: u32,
+    connected_at: Option<Instant>,
+    retry_at: Option<Instant>,
+}
+
+impl ReconnectBackoff {
+    pub fn new(config: &DeviceConfig) -> ReconnectBackoff {
+        return ReconnectBackoff {
+            min_delay: config.reconnect_min_delay,
+            max_delay: config.reconnect_max_delay,
+            jitter: config.reconnect_jitter,
+            reset_after: config.reconnect_reset_after,
+            failures: 0,
+            connected_at: None,
+            retry_at: None,
+        };
+    }
+
+    pub fn connected(&mut self, now: Instant) {
+        self.connected_at = Some(now);
+    }
+
+    /// Registers the end of a connection attempt, successful or not. Returns the
+    /// delay before the next attempt.
+    pub fn disconnected(&mut self, now: Instant) -> Duration {
+        let is_success = match self.connected_at.take() {
+            Some(connected_at) => now.duration_since(connected_at) >= self.reset_after,
+            None => false,
+        };
+        if is_success {
+            self.failures = 0;
+        } else {
+            self.failures = self.failures.saturating_add(1);
+        }
+
+        let exponent = self.failures.saturating_sub(1).min(31);
+        let delay = self
+            .min_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        // Clamped again, so that jitter never takes the delay over the maximum
+        let delay = delay.mul_f64(1.0 + jitter).min(self.max_delay);
+        self.retry_at = Some(now + delay);
+        return delay;
+    }
+
+    /// Whether a connection may be attempted now.
+    pub fn can_attempt(&self, now: Instant) -> bool {
+        return match self.retry_at {
+            Some(retry_at) => now >= retry_at,
+            None => true,
+        };
+    }
+
+    /// Consecutive attempts that did not result in a lasting connection.
+    pub fn failures(&self) -> u32 {
+        return self.failures;
+    }
+}
@@ -0,0 +1,166 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::config::{Config, LogFormat};
+use crate::timestamp::format_rfc3339;
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::SystemTime;
+
+/// Writes log records to stderr, filtered by module and by the `device` field of
+/// the record.
+pub struct Logger {
+    level: LevelFilter,
+    /// Longest module path first, so that the most specific filter wins.
+    filters: Vec<(String, LevelFilter)>,
+    device_levels: HashMap<String, LevelFilter>,
+    format: LogFormat,
+    timestamps: bool,
+}
+
+impl Logger {
+    pub fn new(config: &Config) -> Logger {
+        let mut filters = config.logging.filters.clone();
+        filters.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        let device_levels = config
+            .devices
+            .iter()
+            .filter_map(|(address, device_config)| {
+                device_config
+                    .log_level
+                    .map(|level| (address.to_string(), level))
+            })
+            .collect();
+        return Logger {
+            level: config.logging.level,
+            filters: filters,
+            device_levels: device_levels,
+            format: config.logging.format,
+            timestamps: config.logging.timestamps,
+        };
+    }
+
+    /// Installs the logger. Can only be called once.
+    pub fn init(config: &Config) {
+        let logger = Logger::new(config);
+        log::set_max_level(logger.max_level());
+        if let Err(err) = log::set_boxed_logger(Box::new(logger)) {
+            eprintln!("Warning! Cannot install logger: {}", err);
+        }
+    }
+
+    /// Most verbose level of any module or device.
+    pub fn max_level(&self) -> LevelFilter {
+        let filters = self.filters.iter().map(|(_, level)| *level);
+        let devices = self.device_levels.values().copied();
+        return filters.chain(devices).fold(self.level, std::cmp::max);
+    }
+
+    /// Whether the record passes the filter of its device, or else of its module.
+    pub fn is_logged(&self, record: &Record) -> bool {
+        return record.level() <= self.level_of(record);
+    }
+
+    fn level_of(&self, record: &Record) -> LevelFilter {
+        if let Some(device) = record.key_values().get(Key::from_str("device")) {
+            if let Some(level) = self.device_levels.get(&device.to_string()) {
+                return *level;
+            }
+        }
+        for (module, level) in &self.filters {
+            let is_match = match record.target().strip_prefix(module.as_str()) {
+                Some(rest) => rest.len() == 0 || rest.starts_with("::"),
+                None => false,
+            };
+            if is_match {
+                return *level;
+            }
+        }
+        return self.level;
+    }
+
+    pub fn format(&self, record: &Record, now: SystemTime) -> String {
+        let mut fields = Fields(Vec::new());
+        _ = record.key_values().visit(&mut fields);
+
+        return match self.format {
+            LogFormat::Text => {
+                let mut line = String::new();
+                if self.timestamps {
+                    line.push_str(&format_rfc3339(now));
+                    line.push(' ');
+                }
+                line.push_str(&format!(
+                    "{0:<5} {1}: {2}",
+                    record.level(),
+                    record.target(),
+                    record.args()
+                ));
+                for (key, value) in fields.0 {
+                    match value {
+                        serde_json::Value::String(value) => {
+                            line.push_str(&format!(" {0}={1}", key, value))
+                        }
+                        value => line.push_str(&format!(" {0}={1}", key, value)),
+                    }
+                }
+                line
+            }
+            LogFormat::Json => {
+                let mut object = serde_json::Map::new();
+                if self.timestamps {
+                    object.insert("time".to_string(), format_rfc3339(now).into());
+                }
+                object.insert("level".to_string(), record.level().as_str().into());
+                object.insert("target".to_string(), record.target().into());
+                object.insert("message".to_string(), record.args().to_string().into());
+                for (key, value) in fields.0 {
+                    object.insert(key, value);
+                }
+                serde_json::Value::Object(object).to_string()
+            }
+        };
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Device filters need the fields, so the record is checked again in log()
+        return metadata.level() <= self.max_level();
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.is_logged(record) {
+            return;
+        }
+        let line = self.format(record, SystemTime::now());
+        _ = writeln!(std::io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        _ = std::io::stderr().flush();
+    }
+}
+
+/// Key-value fields of a record, in the order they were given.
+struct Fields(Vec<(String, serde_json::Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = if let Some(value) = value.to_bool() {
+            serde_json::Value::from(value)
+        } else if let Some(value) = value.to_i64() {
+            serde_json::Value::from(value)
+        } else if let Some(value) = value.to_u64() {
+            serde_json::Value::from(value)
+        } else if let Some(value) = value.to_f64() {
+            serde_json::Value::from(value)
+        } else {
+            serde_json::Value::from(value.to_string())
+        };
+        self.0.push((key.as_str().to_string(), value));
+        return Ok(());
+    }
+}
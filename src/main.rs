@@ -1,43 +1,177 @@
 // Author: Jarkko Pöyry
 // See LICENSE for License
 
+mod bluetooth;
+mod cli;
 mod config;
+mod device_matcher;
+mod inventory;
 mod itag_swarm_manager;
+mod logging;
 mod mqtt_client;
+mod probe;
+mod scan;
+mod sd_notify;
+#[cfg(test)]
+mod tests;
+mod timestamp;
+mod topic_template;
 
+use crate::bluetooth::BluerBackend;
+use crate::cli::{Command, USAGE};
 use crate::config::Config;
+use crate::inventory::Inventory;
 use crate::itag_swarm_manager::ITagSwarmManager;
+use crate::logging::Logger;
 use crate::mqtt_client::MqttClient;
+use crate::probe::probe;
+use crate::scan::scan;
+use crate::sd_notify::SdNotify;
+use log::{error, info};
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let config_file = match std::env::args().nth(1) {
-        Some(path) => path,
-        None => {
-            eprintln!("Error: Config file path was not given on command line. Usage: itag2mqttd path/to/config.ini");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match Command::parse(&args) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("Error: {0}\n\n{1}", err, USAGE);
             process::exit(1);
         }
     };
 
-    let config = match Config::read(&config_file) {
+    match command {
+        Command::Help => println!("{0}", USAGE),
+        Command::Run { config_file } => run(read_config(&config_file)).await,
+        Command::CheckConfig { config_file } => check_config(read_config(&config_file)),
+        Command::Scan {
+            config_file,
+            duration,
+        } => scan_tags(read_config(&config_file), duration).await,
+        Command::Probe {
+            config_file,
+            address,
+        } => probe_tag(read_config(&config_file), address).await,
+        Command::Adopt {
+            config_file,
+            address,
+        } => adopt_tag(read_config(&config_file), address),
+    }
+}
+
+/// Reads the config and sets up logging as it says. Exits if the config is invalid.
+fn read_config(config_file: &str) -> Config {
+    let config = match Config::read(config_file) {
         Ok(config) => config,
         Err(err) => {
             eprintln!("Error: Failed to parse config: {0}", err);
             process::exit(1);
         }
     };
+    Logger::init(&config);
+    return config;
+}
+
+fn load_inventory(config: &Config) -> Inventory {
+    return match Inventory::load(config.inventory_file.as_deref()) {
+        Ok(inventory) => inventory,
+        Err(err) => {
+            error!("{0}", err);
+            process::exit(1);
+        }
+    };
+}
 
-    let session = match bluer::Session::new().await {
-        Ok(session) => session,
+async fn open_backend() -> BluerBackend {
+    return match bluer::Session::new().await {
+        Ok(session) => BluerBackend::new(session),
         Err(err) => {
-            eprintln!("Cannot open bluetooth session {0}", err);
+            error!("Cannot open bluetooth session: {0}", err);
             process::exit(1);
         }
     };
+}
+
+async fn run(config: Config) {
+    let inventory = load_inventory(&config);
+    let backend = open_backend().await;
+
+    let (mqttc, mqtt_events) = MqttClient::new(&config);
 
-    let mqttc = MqttClient::new(&config);
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(err) => {
+            error!("Cannot install signal handler: {0}", err);
+            process::exit(1);
+        }
+    };
+    let shutdown = async move {
+        tokio::select! {
+            _ = sigterm.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+        info!("Shutting down");
+    };
 
-    let manager = ITagSwarmManager::new(config, mqttc);
-    manager.run_async(session).await;
+    let manager = ITagSwarmManager::new(config, inventory, mqttc, SdNotify::from_env());
+    manager
+        .run_async(Arc::new(backend), mqtt_events, shutdown)
+        .await;
+}
+
+fn check_config(config: Config) {
+    _ = load_inventory(&config);
+    print!("{0}", config.to_ini());
+}
+
+async fn scan_tags(config: Config, duration: Duration) {
+    let inventory = load_inventory(&config);
+    let backend = open_backend().await;
+
+    info!("Scanning for {0} seconds", duration.as_secs());
+    let tags = match scan(&config, &inventory, &backend, duration).await {
+        Ok(tags) => tags,
+        Err(err) => {
+            error!("{0}", err);
+            process::exit(1);
+        }
+    };
+    if tags.len() == 0 {
+        println!("No tags found");
+    }
+    for tag in tags {
+        println!("{0}", tag.describe());
+    }
+}
+
+async fn probe_tag(config: Config, address: bluer::Address) {
+    let backend = open_backend().await;
+
+    match probe(&config, &backend, address).await {
+        Ok(report) => println!("{0}", report.describe()),
+        Err(err) => {
+            error!("{0}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn adopt_tag(config: Config, address: bluer::Address) {
+    if config.inventory_file.is_none() {
+        error!("Missing 'inventory_file' in [bluetooth] block");
+        process::exit(1);
+    }
+    let inventory = load_inventory(&config);
+    match inventory.insert(&address) {
+        Ok(true) => println!("Added {0} to inventory", address),
+        Ok(false) => println!("{0} is already in inventory", address),
+        Err(err) => {
+            error!("{0}", err);
+            process::exit(1);
+        }
+    }
 }
@@ -1,66 +1,699 @@
 // Author: Jarkko Pöyry
 // See LICENSE for License
 
-use crate::config::Config;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
-use std::time::Duration;
+use crate::config::{parse_hex, Config, MqttTopics, PayloadFormat};
+use crate::timestamp::format_rfc3339;
+use crate::topic_template::{TopicArgs, TopicTemplate};
+use log::{debug, info, warn};
+use rumqttc::{
+    AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration, Transport,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
 use tokio::task;
 
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+/// Inbound events received from the broker.
+pub enum MqttEvent {
+    /// Connection to the broker is up.
+    Connected,
+    /// Home Assistant announced itself online, and has lost all non-retained state.
+    HomeAssistantOnline,
+    /// Alert command for a device, payload is `off|mild|high` with optional duration in seconds.
+    AlertCommand { device_id: [u8; 6], payload: String },
+}
+
+/// Where the device is seen from. Included in JSON payloads.
+#[derive(Clone, Default)]
+pub struct DeviceContext {
+    /// Name of the adapter the device is connected through.
+    pub adapter: Option<String>,
+    /// Latest RSSI of the device.
+    pub rssi: Option<i16>,
+}
+
 pub struct MqttClient {
     client: AsyncClient,
+    eventloop_task: Mutex<Option<task::JoinHandle<()>>>,
+    /// Set on shutdown. Event loop disconnects once every message has been acknowledged.
+    is_disconnecting: Arc<AtomicBool>,
+    is_connected: Arc<AtomicBool>,
+    /// When the event loop last handled an event. Idle connection still has pings.
+    last_progress: Arc<Mutex<Instant>>,
+    discovery_prefix: Option<String>,
+    /// Start of Home Assistant ids, from `topic_prefix`. Keeps the entities of daemons
+    /// that share a broker apart.
+    discovery_id_prefix: String,
+    topics: MqttTopics,
+    /// Availability of the daemon itself. Retained, and set offline by Last Will if the daemon dies.
+    bridge_state_topic: String,
+    payload_format: PayloadFormat,
+    /// Configured aliases and names of devices.
+    aliases: HashMap<[u8; 6], String>,
+    names: HashMap<[u8; 6], String>,
+    /// Sequence number of the latest JSON message of each device.
+    sequences: Mutex<HashMap<[u8; 6], u64>>,
 }
 
 impl MqttClient {
-    pub fn new(config: &Config) -> MqttClient {
-        let mut options =
-            MqttOptions::new("itag2mqttd", config.mqtt_host.clone(), config.mqtt_port);
-        options.set_keep_alive(Duration::from_secs(60));
+    pub fn new(config: &Config) -> (MqttClient, mpsc::UnboundedReceiver<MqttEvent>) {
+        let bridge_state_topic = config.mqtt_topics.bridge_state.render(&TopicArgs {
+            address: "",
+            alias: "",
+            adapter: "",
+        });
+
+        let mut options = MqttOptions::new(
+            config.mqtt_client_id.clone(),
+            config.mqtt_host.clone(),
+            config.mqtt_port,
+        );
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            bridge_state_topic.clone(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &config.mqtt_username {
+            let password = config.mqtt_password.clone().unwrap_or_default();
+            options.set_credentials(username.clone(), password);
+        }
+        if let Some(tls) = &config.mqtt_tls {
+            let transport = match &tls.ca {
+                Some(ca) => Transport::tls_with_config(TlsConfiguration::Simple {
+                    ca: ca.clone(),
+                    alpn: None,
+                    client_auth: tls.client_auth.clone(),
+                }),
+                None => Transport::tls_with_default_config(),
+            };
+            options.set_transport(transport);
+        }
+
+        // Home Assistant ids allow only letters, digits, '_' and '-'
+        let discovery_id_prefix: String = config
+            .mqtt_topics
+            .prefix
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+                true => c,
+                false => '_',
+            })
+            .collect();
+
+        let mut aliases: HashMap<[u8; 6], String> = HashMap::new();
+        let mut names: HashMap<[u8; 6], String> = HashMap::new();
+        for (address, device_config) in &config.devices {
+            if let Some(alias) = &device_config.alias {
+                aliases.insert(address.0, alias.clone());
+            }
+            if let Some(name) = &device_config.name {
+                names.insert(address.0, name.clone());
+            }
+        }
+        let alias_devices: HashMap<String, [u8; 6]> = aliases
+            .iter()
+            .map(|(device_id, alias)| (alias.clone(), *device_id))
+            .collect();
 
         let (client, mut eventloop) = AsyncClient::new(options, 10);
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        let status_topic = match &config.ha_discovery_prefix {
+            Some(discovery_prefix) => Some(format!("{}/status", discovery_prefix)),
+            None => None,
+        };
+        let subscribe_client = client.clone();
+        let online_topic = bridge_state_topic.clone();
+        let alert_set_topic = config.mqtt_topics.alert_set.clone();
+        let is_disconnecting = Arc::new(AtomicBool::new(false));
+        let eventloop_is_disconnecting = is_disconnecting.clone();
+        let is_connected = Arc::new(AtomicBool::new(false));
+        let eventloop_is_connected = is_connected.clone();
+        let last_progress = Arc::new(Mutex::new(Instant::now()));
+        let eventloop_last_progress = last_progress.clone();
 
-        task::spawn(async move {
+        let eventloop_task = task::spawn(async move {
+            let mut is_disconnect_sent = false;
             loop {
-                let _ = eventloop.poll().await;
+                // Closing the connection while acks are still unread resets it, and the
+                // broker may then drop the last messages. So wait for the acks first.
+                if eventloop_is_disconnecting.load(Ordering::Relaxed)
+                    && !is_disconnect_sent
+                    && eventloop.state.inflight() == 0
+                {
+                    is_disconnect_sent = subscribe_client.try_disconnect().is_ok();
+                }
+
+                let event = eventloop.poll().await;
+
+                // Only answers from the broker count, as polling itself succeeds while the
+                // connection is stuck
+                if let Ok(Event::Incoming(
+                    Packet::ConnAck(_) | Packet::PubAck(_) | Packet::PubComp(_) | Packet::PingResp,
+                )) = &event
+                {
+                    *eventloop_last_progress.lock().unwrap() = Instant::now();
+                }
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        eventloop_is_connected.store(true, Ordering::Relaxed);
+                        let _ = sender.send(MqttEvent::Connected);
+                        let _ = subscribe_client.try_publish(
+                            online_topic.clone(),
+                            QoS::AtLeastOnce,
+                            true,
+                            "online",
+                        );
+
+                        // Session is not persisted, so subscriptions must be renewed on every connect
+                        if let Some(status_topic) = &status_topic {
+                            let _ = subscribe_client.try_subscribe(status_topic, QoS::AtLeastOnce);
+                        }
+                        let _ = subscribe_client
+                            .try_subscribe(alert_set_topic.subscription_filter(), QoS::AtLeastOnce);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if Some(&publish.topic) == status_topic.as_ref() {
+                            if publish.payload.as_ref() == b"online" {
+                                let _ = sender.send(MqttEvent::HomeAssistantOnline);
+                            }
+                        } else if let Some(device_id) = alert_set_topic
+                            .match_topic(&publish.topic)
+                            .and_then(|(placeholder, device)| match placeholder {
+                                // Devices without alias use the address as alias
+                                "alias" => alias_devices
+                                    .get(device)
+                                    .copied()
+                                    .or_else(|| parse_device_id(device)),
+                                _ => parse_device_id(device),
+                            })
+                        {
+                            let payload = String::from_utf8_lossy(&publish.payload).to_string();
+                            let _ = sender.send(MqttEvent::AlertCommand { device_id, payload });
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                        // Disconnect was requested and everything before it has been sent
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        if eventloop_is_connected.swap(false, Ordering::Relaxed) {
+                            warn!("Lost connection to MQTT broker: {0}", err);
+                        } else {
+                            debug!("Cannot connect to MQTT broker: {0}", err);
+                        }
+                    }
+                }
             }
         });
 
-        return MqttClient { client: client };
+        let mqttc = MqttClient {
+            client: client,
+            eventloop_task: Mutex::new(Some(eventloop_task)),
+            is_disconnecting: is_disconnecting,
+            is_connected: is_connected,
+            last_progress: last_progress,
+            discovery_prefix: config.ha_discovery_prefix.clone(),
+            discovery_id_prefix: discovery_id_prefix,
+            topics: config.mqtt_topics.clone(),
+            bridge_state_topic: bridge_state_topic,
+            payload_format: config.mqtt_payload_format,
+            aliases: aliases,
+            names: names,
+            sequences: Mutex::new(HashMap::new()),
+        };
+        return (mqttc, receiver);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        return self.is_connected.load(Ordering::Relaxed);
+    }
+
+    /// Whether the broker is answering. Returns false if nothing has been acknowledged
+    /// for longer than keep-alive pings should take.
+    pub fn is_making_progress(&self) -> bool {
+        return self.last_progress.lock().unwrap().elapsed() < KEEP_ALIVE * 2;
+    }
+
+    /// Marks the daemon offline and disconnects cleanly. Returns when all queued
+    /// messages have been sent.
+    pub async fn disconnect(&self) {
+        self.is_disconnecting.store(true, Ordering::Relaxed);
+        let _ = self
+            .client
+            .publish(
+                self.bridge_state_topic.clone(),
+                QoS::AtLeastOnce,
+                true,
+                "offline",
+            )
+            .await;
+
+        let eventloop_task = self.eventloop_task.lock().unwrap().take();
+        if let Some(eventloop_task) = eventloop_task {
+            let _ = eventloop_task.await;
+        }
     }
 
     pub fn publish_device(
         &self,
         device_id: &[u8; 6],
+        context: &DeviceContext,
         retained: bool,
         is_present: bool,
         is_button_clicked: bool,
     ) {
-        let device_id_str = device_id
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<String>();
-        let presence_topic = format!("itag/{}/presence", device_id_str);
-        let button_topic = format!("itag/{}/button/click", device_id_str);
-        let false_bytes: [u8; 1] = ['0' as u8];
-        let true_bytes: [u8; 1] = ['1' as u8];
-
         // \note: we use try_publish instead of publish. This avoids backpressure
         //        which we do not want in the itag loop. If we would get backpressure,
         //        events are discarded. That is less bad than blocking the itag loop.
+        let messages = self.device_messages(device_id, context, is_present, is_button_clicked);
+        for (topic, payload) in messages {
+            let _ = self
+                .client
+                .try_publish(topic, QoS::AtLeastOnce, retained, payload);
+        }
+    }
+
+    /// Publishes presence of the device with the button released, like `publish_device`.
+    /// Waits for room in the queue instead of discarding, for announcements that must
+    /// not get lost. Not for the itag loop.
+    pub async fn announce_device(
+        &self,
+        device_id: &[u8; 6],
+        context: &DeviceContext,
+        retained: bool,
+        is_present: bool,
+    ) {
+        for (topic, payload) in self.device_messages(device_id, context, is_present, false) {
+            let _ = self
+                .client
+                .publish(topic, QoS::AtLeastOnce, retained, payload)
+                .await;
+        }
+    }
+
+    /// Returns (topic, payload) of the presence and button messages of the device.
+    fn device_messages(
+        &self,
+        device_id: &[u8; 6],
+        context: &DeviceContext,
+        is_present: bool,
+        is_button_clicked: bool,
+    ) -> [(String, String); 2] {
+        return [
+            (
+                self.device_topic(&self.topics.presence, device_id),
+                self.device_payload(device_id, context, Value::Bool(is_present)),
+            ),
+            (
+                self.device_topic(&self.topics.button_click, device_id),
+                self.device_payload(device_id, context, Value::Bool(is_button_clicked)),
+            ),
+        ];
+    }
+
+    /// Publishes classified button action: single, double, triple or hold.
+    pub fn publish_button_action(
+        &self,
+        device_id: &[u8; 6],
+        context: &DeviceContext,
+        action: &str,
+    ) {
+        let action_topic = self.device_topic(&self.topics.button_action, device_id);
+
         let _ = self.client.try_publish(
-            presence_topic,
+            action_topic,
             QoS::AtLeastOnce,
-            retained,
-            if is_present { true_bytes } else { false_bytes },
+            false,
+            self.device_payload(device_id, context, json!(action)),
         );
+    }
+
+    /// Publishes the outcome of an alert command.
+    pub fn publish_alert_result(&self, device_id: &[u8; 6], result: Result<(), String>) {
+        let result_topic = self.device_topic(&self.topics.alert_result, device_id);
+        let payload = match result {
+            Ok(()) => "ok".to_string(),
+            Err(err) => format!("error: {}", err),
+        };
+
+        let _ = self
+            .client
+            .try_publish(result_topic, QoS::AtLeastOnce, false, payload);
+    }
+
+    /// Publishes battery level in percent. Retained, as the level is read rarely.
+    pub fn publish_battery(&self, device_id: &[u8; 6], context: &DeviceContext, level: u8) {
+        let battery_topic = self.device_topic(&self.topics.battery, device_id);
+
         let _ = self.client.try_publish(
-            button_topic,
+            battery_topic,
             QoS::AtLeastOnce,
-            retained,
-            if is_button_clicked {
-                true_bytes
-            } else {
-                false_bytes
-            },
+            true,
+            self.device_payload(device_id, context, json!(level)),
         );
     }
+
+    /// Publishes RSSI of the device as seen by each adapter, and the best of them.
+    pub fn publish_rssi(
+        &self,
+        device_id: &[u8; 6],
+        context: &DeviceContext,
+        readings: &[(String, i16)],
+    ) {
+        let best_rssi = match readings.iter().map(|(_, rssi)| *rssi).max() {
+            Some(best_rssi) => best_rssi,
+            None => return,
+        };
+
+        let _ = self.client.try_publish(
+            self.device_topic(&self.topics.rssi, device_id),
+            QoS::AtLeastOnce,
+            false,
+            self.device_payload(device_id, context, json!(best_rssi)),
+        );
+        let address = device_id_to_str(device_id);
+        let alias = self.device_alias(device_id);
+        for (adapter_name, rssi) in readings {
+            let adapter_topic = self.topics.adapter_rssi.render(&TopicArgs {
+                address: &address,
+                alias: &alias,
+                adapter: adapter_name,
+            });
+            let adapter_context = DeviceContext {
+                adapter: Some(adapter_name.clone()),
+                rssi: Some(*rssi),
+            };
+            let _ = self.client.try_publish(
+                adapter_topic,
+                QoS::AtLeastOnce,
+                false,
+                self.device_payload(device_id, &adapter_context, json!(rssi)),
+            );
+        }
+    }
+
+    /// Publishes which optional features the tag supports, as a JSON object of booleans.
+    pub fn publish_capabilities(&self, device_id: &[u8; 6], capabilities: &[(&str, bool)]) {
+        let capabilities_topic = self.device_topic(&self.topics.capabilities, device_id);
+        let mut payload = serde_json::Map::new();
+        for (name, is_supported) in capabilities {
+            payload.insert(name.to_string(), Value::Bool(*is_supported));
+        }
+
+        let _ = self.client.try_publish(
+            capabilities_topic,
+            QoS::AtLeastOnce,
+            true,
+            Value::Object(payload).to_string(),
+        );
+    }
+
+    /// Publishes a one-shot device event, such as `battery_low`.
+    pub fn publish_event(&self, device_id: &[u8; 6], context: &DeviceContext, event: &str) {
+        let event_topic = self.device_topic(&self.topics.event, device_id);
+
+        let _ = self.client.try_publish(
+            event_topic,
+            QoS::AtLeastOnce,
+            false,
+            self.device_payload(device_id, context, json!(event)),
+        );
+    }
+
+    /// Publishes connection diagnostics of the device as a JSON object. Retained, so
+    /// that the latest state can be inspected at any time.
+    pub fn publish_diagnostics(&self, device_id: &[u8; 6], diagnostics: Value) {
+        let diagnostics_topic = self.device_topic(&self.topics.diagnostics, device_id);
+
+        let _ = self.client.try_publish(
+            diagnostics_topic,
+            QoS::AtLeastOnce,
+            true,
+            diagnostics.to_string(),
+        );
+    }
+
+    /// Publishes Home Assistant discovery configs for the device. The configs are
+    /// retained so that Home Assistant picks them up whenever it connects. Waits for
+    /// room in the queue, as a discarded config would leave the entity missing.
+    pub async fn publish_discovery(&self, device_id: &[u8; 6]) {
+        let discovery_prefix = match &self.discovery_prefix {
+            Some(discovery_prefix) => discovery_prefix,
+            None => return,
+        };
+        let node_id = self.discovery_node_id(device_id);
+
+        for (component, object_id, config) in self.discovery_configs(device_id) {
+            let _ = self
+                .client
+                .publish(
+                    discovery_topic(discovery_prefix, component, &node_id, object_id),
+                    QoS::AtLeastOnce,
+                    true,
+                    config.to_string(),
+                )
+                .await;
+        }
+    }
+
+    /// Removes the Home Assistant discovery configs of the device. Empty retained
+    /// payload clears the retained config and removes the entity from Home Assistant.
+    pub async fn remove_discovery(&self, device_id: &[u8; 6]) {
+        let discovery_prefix = match &self.discovery_prefix {
+            Some(discovery_prefix) => discovery_prefix,
+            None => return,
+        };
+        let node_id = self.discovery_node_id(device_id);
+        let empty_bytes: [u8; 0] = [];
+
+        for (component, object_id, _config) in self.discovery_configs(device_id) {
+            let _ = self
+                .client
+                .publish(
+                    discovery_topic(discovery_prefix, component, &node_id, object_id),
+                    QoS::AtLeastOnce,
+                    true,
+                    empty_bytes,
+                )
+                .await;
+        }
+    }
+
+    /// Clears the retained topics of the device, so that it disappears from the broker.
+    pub async fn clear_retained(&self, device_id: &[u8; 6]) {
+        let empty_bytes: [u8; 0] = [];
+        for template in [
+            &self.topics.presence,
+            &self.topics.button_click,
+            &self.topics.battery,
+            &self.topics.capabilities,
+            &self.topics.diagnostics,
+        ] {
+            let _ = self
+                .client
+                .publish(
+                    self.device_topic(template, device_id),
+                    QoS::AtLeastOnce,
+                    true,
+                    empty_bytes,
+                )
+                .await;
+        }
+    }
+
+    /// Home Assistant id of the device, e.g. itag_ffff12345678.
+    fn discovery_node_id(&self, device_id: &[u8; 6]) -> String {
+        return format!(
+            "{}_{}",
+            self.discovery_id_prefix,
+            device_id_to_str(device_id)
+        );
+    }
+
+    /// Returns (component, object id, config) of every Home Assistant entity of the device.
+    fn discovery_configs(&self, device_id: &[u8; 6]) -> Vec<(&'static str, &'static str, Value)> {
+        let device_id_str = device_id_to_str(device_id);
+        let node_id = self.discovery_node_id(device_id);
+        let device = json!({
+            "identifiers": [node_id],
+            "connections": [["bluetooth", device_id_to_mac(device_id)]],
+            "name": match self.names.get(device_id) {
+                Some(name) => name.clone(),
+                None => format!("iTag {}", device_id_str),
+            },
+            "model": "iTag",
+        });
+        let availability = json!([{
+            "topic": self.bridge_state_topic,
+            "payload_available": "online",
+            "payload_not_available": "offline",
+        }]);
+
+        // Raw payloads are bare values, JSON payloads carry the value in `state`
+        let (value, is_on) = match self.payload_format {
+            PayloadFormat::Raw => ("value", "value == '1'"),
+            PayloadFormat::Json => ("value_json.state", "value_json.state"),
+        };
+
+        let presence_config = json!({
+            "name": "Presence",
+            "unique_id": format!("{}_presence", node_id),
+            "object_id": format!("{}_presence", node_id),
+            "state_topic": self.device_topic(&self.topics.presence, device_id),
+            "value_template": format!("{{{{ 'ON' if {} else 'OFF' }}}}", is_on),
+            "device_class": "presence",
+            "availability": availability,
+            "device": device,
+        });
+        // Event entities require a JSON object with an event_type, so map the value
+        // in the template.
+        let click_config = json!({
+            "name": "Button",
+            "unique_id": format!("{}_click", node_id),
+            "object_id": format!("{}_click", node_id),
+            "state_topic": self.device_topic(&self.topics.button_click, device_id),
+            "value_template": format!(
+                "{{\"event_type\": \"{{{{ 'press' if {} else 'release' }}}}\"}}",
+                is_on
+            ),
+            "event_types": ["press", "release"],
+            "device_class": "button",
+            "availability": availability,
+            "device": device,
+        });
+        let action_config = json!({
+            "name": "Button action",
+            "unique_id": format!("{}_action", node_id),
+            "object_id": format!("{}_action", node_id),
+            "state_topic": self.device_topic(&self.topics.button_action, device_id),
+            "value_template": format!("{{\"event_type\": \"{{{{ {} }}}}\"}}", value),
+            "event_types": ["single", "double", "triple", "hold"],
+            "device_class": "button",
+            "availability": availability,
+            "device": device,
+        });
+        let battery_config = json!({
+            "name": "Battery",
+            "unique_id": format!("{}_battery", node_id),
+            "object_id": format!("{}_battery", node_id),
+            "state_topic": self.device_topic(&self.topics.battery, device_id),
+            "value_template": format!("{{{{ {} }}}}", value),
+            "unit_of_measurement": "%",
+            "device_class": "battery",
+            "state_class": "measurement",
+            "entity_category": "diagnostic",
+            "availability": availability,
+            "device": device,
+        });
+        let rssi_config = json!({
+            "name": "RSSI",
+            "unique_id": format!("{}_rssi", node_id),
+            "object_id": format!("{}_rssi", node_id),
+            "state_topic": self.device_topic(&self.topics.rssi, device_id),
+            "value_template": format!("{{{{ {} }}}}", value),
+            "unit_of_measurement": "dBm",
+            "device_class": "signal_strength",
+            "state_class": "measurement",
+            "entity_category": "diagnostic",
+            "availability": availability,
+            "device": device,
+        });
+
+        return vec![
+            ("binary_sensor", "presence", presence_config),
+            ("event", "click", click_config),
+            ("event", "action", action_config),
+            ("sensor", "battery", battery_config),
+            ("sensor", "rssi", rssi_config),
+        ];
+    }
+
+    /// Formats payload of a device message. Raw payload is the bare value, booleans
+    /// as `0`/`1`. JSON payload wraps the value with metadata and a sequence number
+    /// which grows by one on every message of the device. Gaps in the sequence reveal
+    /// messages discarded by try_publish.
+    fn device_payload(&self, device_id: &[u8; 6], context: &DeviceContext, state: Value) -> String {
+        if self.payload_format == PayloadFormat::Raw {
+            return match state {
+                Value::Bool(true) => "1".to_string(),
+                Value::Bool(false) => "0".to_string(),
+                Value::String(state) => state,
+                state => state.to_string(),
+            };
+        }
+
+        let seq = {
+            let mut sequences = self.sequences.lock().unwrap();
+            let seq = sequences.entry(*device_id).or_insert(0);
+            *seq += 1;
+            *seq
+        };
+        return json!({
+            "state": state,
+            "timestamp": format_rfc3339(SystemTime::now()),
+            "adapter": context.adapter,
+            "rssi": context.rssi,
+            "seq": seq,
+        })
+        .to_string();
+    }
+
+    fn device_topic(&self, template: &TopicTemplate, device_id: &[u8; 6]) -> String {
+        let address = device_id_to_str(device_id);
+        return template.render(&TopicArgs {
+            address: &address,
+            alias: &self.device_alias(device_id),
+            adapter: "",
+        });
+    }
+
+    /// Configured alias of the device, or its address if it has none.
+    fn device_alias(&self, device_id: &[u8; 6]) -> String {
+        return match self.aliases.get(device_id) {
+            Some(alias) => alias.clone(),
+            None => device_id_to_str(device_id),
+        };
+    }
+}
+
+fn device_id_to_str(device_id: &[u8; 6]) -> String {
+    return device_id
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+}
+
+/// Parses device id from lowercase or uppercase hex without separators.
+pub fn parse_device_id(device_id_str: &str) -> Option<[u8; 6]> {
+    return parse_hex(device_id_str)?.try_into().ok();
+}
+
+fn device_id_to_mac(device_id: &[u8; 6]) -> String {
+    return device_id
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(":");
+}
+
+fn discovery_topic(
+    discovery_prefix: &str,
+    component: &str,
+    node_id: &str,
+    object_id: &str,
+) -> String {
+    return format!(
+        "{}/{}/{}/{}/config",
+        discovery_prefix, component, node_id, object_id
+    );
 }
@@ -0,0 +1,169 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::bluetooth::{
+    write_alert_level, Backend, Device, ServiceInfo, BATTERY_LEVEL_CHARACTERISTIC, BATTERY_SERVICE,
+    IMMEDIATE_ALERT_SERVICE,
+};
+use crate::config::{AlertLevel, Config};
+use crate::scan::Discovery;
+use std::sync::Arc;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// How long to look for the tag before giving up.
+const FIND_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for other adapters once one has seen the tag.
+const FIND_SETTLE_TIME: Duration = Duration::from_secs(1);
+const BEEP_DURATION: Duration = Duration::from_secs(2);
+
+/// What `probe` found out about a tag.
+pub struct ProbeReport {
+    pub address: bluer::Address,
+    pub adapter_name: String,
+    pub rssi: i16,
+    pub services: Vec<ServiceInfo>,
+    /// Error if the tag could not be made to beep.
+    pub beep_error: Option<String>,
+    pub battery_level: Result<u8, String>,
+}
+
+impl ProbeReport {
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!(
+            "Connected to {0} on {1} ({2} dBm)",
+            self.address, self.adapter_name, self.rssi
+        )];
+        lines.push("Services:".to_string());
+        for service in &self.services {
+            lines.push(format!("  {0}", service.uuid));
+            for char in &service.characteristics {
+                lines.push(format!("    {0} {1}", char.uuid, char.flags.join(",")));
+            }
+        }
+        lines.push(match &self.beep_error {
+            Some(err) => format!("Beep: failed: {0}", err),
+            None => "Beep: ok".to_string(),
+        });
+        lines.push(match &self.battery_level {
+            Ok(level) => format!("Battery: {0}%", level),
+            Err(err) => format!("Battery: unknown: {0}", err),
+        });
+        return lines.join("\n");
+    }
+}
+
+/// Connects to the tag on the adapter that hears it best, lists its GATT services,
+/// makes it beep and reads its battery level. The tag is left connected if it
+/// already was, e.g. by a running daemon.
+pub async fn probe(
+    config: &Config,
+    backend: &dyn Backend,
+    address: bluer::Address,
+) -> Result<ProbeReport, String> {
+    let (rssi, device) = find_device(config, backend, address).await?;
+    let adapter_name = device.adapter_name().to_string();
+
+    let was_connected = device.is_connected().await.unwrap_or(false);
+    if !was_connected {
+        let connect_timeout = config.device_config(&address).connect_timeout;
+        match timeout(connect_timeout, device.connect()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                _ = device.disconnect().await;
+                return Err(format!("Cannot connect to {0}: {1}", address, err));
+            }
+            Err(_elapsed) => {
+                _ = device.disconnect().await;
+                return Err(format!(
+                    "Cannot connect to {0}: connection timeout",
+                    address
+                ));
+            }
+        }
+    }
+
+    let result = inspect(&*device).await;
+    if !was_connected {
+        _ = device.disconnect().await;
+    }
+    let (services, beep_error, battery_level) = result?;
+
+    return Ok(ProbeReport {
+        address: address,
+        adapter_name: adapter_name,
+        rssi: rssi,
+        services: services,
+        beep_error: beep_error,
+        battery_level: battery_level,
+    });
+}
+
+/// Finds the adapter with the strongest signal from the device.
+async fn find_device(
+    config: &Config,
+    backend: &dyn Backend,
+    address: bluer::Address,
+) -> Result<(i16, Arc<dyn Device>), String> {
+    let mut discovery = Discovery::start(config, backend).await?;
+    let mut deadline = Instant::now() + FIND_TIMEOUT;
+    let mut best: Option<(i16, Arc<dyn Device>)> = None;
+    while let Some(device) = discovery.next(deadline).await {
+        if device.address() != address {
+            continue;
+        }
+        let rssi = match device.rssi().await {
+            Ok(Some(rssi)) => rssi,
+            Ok(None) | Err(_) => continue,
+        };
+        if best.is_none() {
+            deadline = deadline.min(Instant::now() + FIND_SETTLE_TIME);
+        }
+        match &best {
+            Some((best_rssi, _)) if *best_rssi >= rssi => {}
+            _ => best = Some((rssi, device)),
+        }
+    }
+    return match best {
+        Some(best) => Ok(best),
+        None => Err(format!(
+            "Device {0} was not found in {1} seconds",
+            address,
+            FIND_TIMEOUT.as_secs()
+        )),
+    };
+}
+
+async fn inspect(
+    device: &dyn Device,
+) -> Result<(Vec<ServiceInfo>, Option<String>, Result<u8, String>), String> {
+    let services = match device.services().await {
+        Ok(services) => services,
+        Err(err) => return Err(format!("Cannot list services: {0}", err)),
+    };
+
+    let beep_error =
+        match write_alert_level(device, IMMEDIATE_ALERT_SERVICE, AlertLevel::High).await {
+            Ok(()) => {
+                sleep(BEEP_DURATION).await;
+                write_alert_level(device, IMMEDIATE_ALERT_SERVICE, AlertLevel::Off)
+                    .await
+                    .err()
+                    .map(|err| err.to_string())
+            }
+            Err(err) => Some(err.to_string()),
+        };
+
+    let battery_level = match device
+        .characteristic(BATTERY_SERVICE, BATTERY_LEVEL_CHARACTERISTIC)
+        .await
+    {
+        Ok(Some(char)) => match char.read().await {
+            Ok(level) => level.first().copied().ok_or("empty value".to_string()),
+            Err(err) => Err(err.to_string()),
+        },
+        Ok(None) => Err("no battery service".to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+
+    return Ok((services, beep_error, battery_level));
+}
@@ -0,0 +1,171 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use crate::bluetooth::{start_discovery, Backend, Device, DeviceEvent};
+use crate::config::Config;
+use crate::device_matcher::DeviceInfo;
+use crate::inventory::{is_known, Inventory};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Duration, Instant};
+use tokio_stream::StreamExt;
+
+/// Discovery on every allowed adapter, for the commands that look for tags without
+/// managing them. Discovery stops when this is dropped.
+pub struct Discovery {
+    devices: mpsc::UnboundedReceiver<Arc<dyn Device>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Discovery {
+    pub async fn start(config: &Config, backend: &dyn Backend) -> Result<Discovery, String> {
+        let adapter_names = match backend.adapter_names().await {
+            Ok(adapter_names) => adapter_names,
+            Err(err) => return Err(format!("Cannot list bluetooth adapters: {0}", err)),
+        };
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut tasks: Vec<JoinHandle<()>> = Vec::new();
+        for adapter_name in adapter_names {
+            let discovery = start_discovery(config, backend, &adapter_name).await;
+            let (adapter, mut stream) = match discovery {
+                Some(discovery) => discovery,
+                None => continue,
+            };
+
+            let sender = sender.clone();
+            tasks.push(tokio::spawn(async move {
+                while let Some(event) = stream.next().await {
+                    if let DeviceEvent::Added(address) = event {
+                        if let Ok(device) = adapter.device(address) {
+                            if sender.send(device).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+
+        if tasks.len() == 0 {
+            return Err("No matching bluetooth adapters present".to_string());
+        }
+        return Ok(Discovery {
+            devices: receiver,
+            tasks: tasks,
+        });
+    }
+
+    /// Next device that came into range or changed, as seen by one adapter. None once
+    /// `deadline` has passed.
+    pub async fn next(&mut self, deadline: Instant) -> Option<Arc<dyn Device>> {
+        return timeout_at(deadline, self.devices.recv())
+            .await
+            .ok()
+            .flatten();
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Tag found by `scan`.
+pub struct ScannedTag {
+    pub address: bluer::Address,
+    /// Advertised name.
+    pub name: Option<String>,
+    /// Name from the [device] block.
+    pub configured_name: Option<String>,
+    /// Why the device is a tag.
+    pub match_reason: String,
+    /// Why the daemon would leave the tag alone, if it would.
+    pub ignored_reason: Option<&'static str>,
+    /// Latest RSSI on every adapter that sees the tag, by adapter name.
+    pub rssi: BTreeMap<String, i16>,
+}
+
+impl ScannedTag {
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!(
+            "{0} {1}",
+            self.address,
+            self.name.as_deref().unwrap_or("(no name)")
+        )];
+        let rssi: Vec<String> = self
+            .rssi
+            .iter()
+            .map(|(adapter_name, rssi)| format!("{0} {1} dBm", adapter_name, rssi))
+            .collect();
+        lines.push(format!("  RSSI: {0}", rssi.join(", ")));
+        lines.push(format!("  Matched: {0}", self.match_reason));
+        if let Some(configured_name) = &self.configured_name {
+            lines.push(format!("  Configured as: {0}", configured_name));
+        }
+        if let Some(ignored_reason) = self.ignored_reason {
+            lines.push(format!("  Ignored: {0}", ignored_reason));
+        }
+        return lines.join("\n");
+    }
+}
+
+/// Lists the tags in range of any adapter during `duration`, without connecting to
+/// them. Tags are recognized like the daemon does.
+pub async fn scan(
+    config: &Config,
+    inventory: &Inventory,
+    backend: &dyn Backend,
+    duration: Duration,
+) -> Result<Vec<ScannedTag>, String> {
+    let mut discovery = Discovery::start(config, backend).await?;
+    let deadline = Instant::now() + duration;
+
+    let mut tags: BTreeMap<bluer::Address, ScannedTag> = BTreeMap::new();
+    while let Some(device) = discovery.next(deadline).await {
+        let rssi = match device.rssi().await {
+            Ok(Some(rssi)) => rssi,
+            Ok(None) | Err(_) => continue,
+        };
+        let address = device.address();
+        let adapter_name = device.adapter_name().to_string();
+        if let Some(tag) = tags.get_mut(&address) {
+            tag.rssi.insert(adapter_name, rssi);
+            continue;
+        }
+
+        let device_info = match DeviceInfo::read(&*device).await {
+            Ok(device_info) => device_info,
+            Err(_err) => continue,
+        };
+        let match_reason = match config.device_matcher.matches(&device_info) {
+            Some(match_reason) => match_reason,
+            None => continue,
+        };
+        let device_config = config.device_config(&address);
+        let ignored_reason = if !device_config.enabled {
+            Some("disabled in config")
+        } else if config.only_known_devices && !is_known(config, inventory, &address) {
+            Some("neither configured nor in inventory, and only_known_devices is set")
+        } else {
+            None
+        };
+        tags.insert(
+            address,
+            ScannedTag {
+                address: address,
+                name: device_info.name,
+                configured_name: device_config.name,
+                match_reason: match_reason,
+                ignored_reason: ignored_reason,
+                rssi: BTreeMap::from([(adapter_name, rssi)]),
+            },
+        );
+    }
+    return Ok(tags.into_values().collect());
+}
@@ -0,0 +1,101 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use log::warn;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Reports readiness, status and liveness to systemd over `$NOTIFY_SOCKET`. Does
+/// nothing when not started by systemd with `Type=notify`.
+pub struct SdNotify {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog_interval: Option<Duration>,
+    is_ready: AtomicBool,
+}
+
+impl SdNotify {
+    /// Reads `$NOTIFY_SOCKET`, and `$WATCHDOG_USEC` if the watchdog is meant for us.
+    pub fn from_env() -> SdNotify {
+        let socket_path = std::env::var("NOTIFY_SOCKET").ok();
+        let is_watchdog_ours = match std::env::var("WATCHDOG_PID") {
+            Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+            Err(_) => true,
+        };
+        let watchdog_usec = match std::env::var("WATCHDOG_USEC") {
+            Ok(usec) if is_watchdog_ours => usec.parse::<u64>().ok(),
+            _ => None,
+        };
+        return SdNotify::new(
+            socket_path.as_deref(),
+            watchdog_usec.map(Duration::from_micros),
+        );
+    }
+
+    /// Notifies the socket at `socket_path`, which is abstract if it starts with `@`.
+    /// systemd expects watchdog pings at least every `watchdog_timeout`.
+    pub fn new(socket_path: Option<&str>, watchdog_timeout: Option<Duration>) -> SdNotify {
+        let socket = match socket_path {
+            Some(socket_path) => match SdNotify::open(socket_path) {
+                Ok(socket) => Some(socket),
+                Err(err) => {
+                    warn!("Cannot open notify socket {0}: {1}", socket_path, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        let watchdog_interval = match &socket {
+            // Ping twice per timeout, as sd_watchdog_enabled(3) recommends
+            Some(_) => watchdog_timeout.map(|timeout| timeout / 2),
+            None => None,
+        };
+        return SdNotify {
+            socket: socket,
+            watchdog_interval: watchdog_interval,
+            is_ready: AtomicBool::new(false),
+        };
+    }
+
+    fn open(socket_path: &str) -> Result<(UnixDatagram, SocketAddr), std::io::Error> {
+        let address = match socket_path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(socket_path)?,
+        };
+        return Ok((UnixDatagram::unbound()?, address));
+    }
+
+    /// How often `watchdog` must be called, if the watchdog is enabled.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        return self.watchdog_interval;
+    }
+
+    /// Tells that startup is complete. Only the first call has an effect.
+    pub fn ready(&self) {
+        if !self.is_ready.swap(true, Ordering::Relaxed) {
+            self.notify("READY=1");
+        }
+    }
+
+    /// Sets the status line shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some((socket, address)) = &self.socket {
+            if let Err(err) = socket.send_to_addr(state.as_bytes(), address) {
+                warn!("Cannot notify systemd: {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+use bytes::BytesMut;
+use rumqttc::{
+    ConnAck, Connect, ConnectReturnCode, Packet, PingResp, PubAck, Publish, QoS, SubAck,
+    SubscribeReasonCode,
+};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration, Instant};
+
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// MQTT broker stand-in. Serves one client at a time, acknowledges everything and
+/// records what the client sends.
+pub struct Broker {
+    port: u16,
+    state: Arc<Mutex<BrokerState>>,
+    outgoing: mpsc::UnboundedSender<Publish>,
+    /// Index of the next message returned by `next_publish`.
+    cursor: Mutex<usize>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    connect: Option<Connect>,
+    subscriptions: Vec<String>,
+    publishes: Vec<Publish>,
+}
+
+impl Broker {
+    /// Listens on a random local port.
+    pub async fn start() -> Broker {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(BrokerState::default()));
+        let (outgoing, mut outgoing_receiver) = mpsc::unbounded_channel();
+
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    serve(stream, &state, &mut outgoing_receiver).await;
+                }
+            });
+        }
+
+        return Broker {
+            port: port,
+            state: state,
+            outgoing: outgoing,
+            cursor: Mutex::new(0),
+        };
+    }
+
+    pub fn port(&self) -> u16 {
+        return self.port;
+    }
+
+    /// CONNECT packet of the latest client.
+    pub fn connect(&self) -> Option<Connect> {
+        return self.state.lock().unwrap().connect.clone();
+    }
+
+    /// Sends a message to the client.
+    pub fn publish(&self, topic: &str, payload: &str) {
+        let _ = self
+            .outgoing
+            .send(Publish::new(topic, QoS::AtMostOnce, payload));
+    }
+
+    /// Waits until the client has subscribed to the topic filter.
+    pub async fn wait_for_subscription(&self, filter: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if self
+                .state
+                .lock()
+                .unwrap()
+                .subscriptions
+                .iter()
+                .any(|subscription| subscription == filter)
+            {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for subscription {}",
+                filter
+            );
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Returns the messages of the client in the order they were received, one at a time.
+    pub async fn next_publish(&self) -> Publish {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            {
+                let state = self.state.lock().unwrap();
+                let mut cursor = self.cursor.lock().unwrap();
+                if let Some(publish) = state.publishes.get(*cursor) {
+                    *cursor += 1;
+                    return publish.clone();
+                }
+            }
+            assert!(Instant::now() < deadline, "Timed out waiting for publish");
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Skips messages until one on the topic arrives.
+    pub async fn next_publish_on(&self, topic: &str) -> Publish {
+        loop {
+            let publish = self.next_publish().await;
+            if publish.topic == topic {
+                return publish;
+            }
+        }
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    state: &Mutex<BrokerState>,
+    outgoing: &mut mpsc::UnboundedReceiver<Publish>,
+) {
+    let mut read_buffer = BytesMut::new();
+    let mut write_buffer = BytesMut::new();
+
+    loop {
+        loop {
+            let packet = match rumqttc::mqttbytes::v4::read(&mut read_buffer, MAX_PACKET_SIZE) {
+                Ok(packet) => packet,
+                Err(rumqttc::mqttbytes::Error::InsufficientBytes(_)) => break,
+                Err(err) => panic!("Invalid packet from client: {:?}", err),
+            };
+            if !handle_packet(packet, state, &mut write_buffer) {
+                return;
+            }
+        }
+        if write_buffer.len() != 0 {
+            if stream.write_all(&write_buffer).await.is_err() {
+                return;
+            }
+            write_buffer.clear();
+        }
+
+        tokio::select! {
+            read = stream.read_buf(&mut read_buffer) => {
+                match read {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {}
+                }
+            },
+            Some(publish) = outgoing.recv() => {
+                publish.write(&mut write_buffer).unwrap();
+            },
+        }
+    }
+}
+
+/// Records the packet and writes the response. Returns false when the client disconnects.
+fn handle_packet(packet: Packet, state: &Mutex<BrokerState>, write_buffer: &mut BytesMut) -> bool {
+    let mut state = state.lock().unwrap();
+    match packet {
+        Packet::Connect(connect) => {
+            state.connect = Some(connect);
+            ConnAck::new(ConnectReturnCode::Success, false)
+                .write(write_buffer)
+                .unwrap();
+        }
+        Packet::Publish(publish) => {
+            if publish.qos == QoS::AtLeastOnce {
+                PubAck::new(publish.pkid).write(write_buffer).unwrap();
+            }
+            state.publishes.push(publish);
+        }
+        Packet::Subscribe(subscribe) => {
+            let mut return_codes: Vec<SubscribeReasonCode> = Vec::new();
+            for filter in subscribe.filters {
+                return_codes.push(SubscribeReasonCode::Success(filter.qos));
+                state.subscriptions.push(filter.path);
+            }
+            SubAck::new(subscribe.pkid, return_codes)
+                .write(write_buffer)
+                .unwrap();
+        }
+        Packet::PingReq => {
+            PingResp.write(write_buffer).unwrap();
+        }
+        Packet::Disconnect => {
+            return false;
+        }
+        _ => {}
+    }
+    return true;
+}
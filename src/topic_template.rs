@@ -0,0 +1,159 @@
+// Author: Jarkko Pöyry
+// See LICENSE for License
+
+/// MQTT topic with placeholders. `{prefix}` is resolved when the template is parsed,
+/// the rest when the topic is rendered:
+/// - `{address}`: device address as lowercase hex without separators
+/// - `{alias}`: device alias
+/// - `{adapter}`: bluetooth adapter name, e.g. hci0
+#[derive(Clone)]
+pub struct TopicTemplate {
+    template: String,
+}
+
+pub struct TopicArgs<'a> {
+    pub address: &'a str,
+    pub alias: &'a str,
+    pub adapter: &'a str,
+}
+
+impl TopicTemplate {
+    /// Parses and validates template. `allowed` lists the placeholders the topic may
+    /// contain. Of each group in `required`, at least one placeholder must be present.
+    pub fn parse(
+        key: &str,
+        template: &str,
+        prefix: &str,
+        allowed: &[&str],
+        required: &[&[&str]],
+    ) -> Result<TopicTemplate, String> {
+        if template.len() == 0 {
+            return Err(format!("Empty topic '{0}'", key));
+        }
+        if template.contains('+') || template.contains('#') {
+            return Err(format!("Wildcards are not allowed in topic '{0}'", key));
+        }
+
+        let mut found: Vec<&str> = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("Unclosed '{{' in topic '{0}'", key)),
+            };
+            let name = &rest[start + 1..end];
+            if name != "prefix" && !allowed.contains(&name) {
+                return Err(format!(
+                    "Unknown placeholder '{{{0}}}' in topic '{1}'",
+                    name, key
+                ));
+            }
+            found.push(name);
+            rest = &rest[end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unmatched '}}' in topic '{0}'", key));
+        }
+        for group in required {
+            if !group.iter().any(|name| found.contains(name)) {
+                let names: Vec<String> =
+                    group.iter().map(|name| format!("{{{0}}}", name)).collect();
+                return Err(format!(
+                    "Topic '{0}' must contain {1}",
+                    key,
+                    names.join(" or ")
+                ));
+            }
+        }
+
+        return Ok(TopicTemplate {
+            template: template.replace("{prefix}", prefix),
+        });
+    }
+
+    /// Like `parse`, but for topics that are subscribed to. Device placeholders must
+    /// be whole topic levels, so that they can be replaced with `+` wildcard.
+    pub fn parse_subscription(
+        key: &str,
+        template: &str,
+        prefix: &str,
+    ) -> Result<TopicTemplate, String> {
+        let topic = TopicTemplate::parse(
+            key,
+            template,
+            prefix,
+            &["address", "alias"],
+            &[&["address", "alias"]],
+        )?;
+
+        let mut device_levels = 0;
+        for level in topic.template.split('/') {
+            if level == "{address}" || level == "{alias}" {
+                device_levels += 1;
+            } else if level.contains('{') {
+                return Err(format!(
+                    "Placeholders must be whole topic levels in topic '{0}'",
+                    key
+                ));
+            }
+        }
+        if device_levels != 1 {
+            return Err(format!(
+                "Topic '{0}' must contain exactly one of {{address}} or {{alias}}",
+                key
+            ));
+        }
+
+        return Ok(topic);
+    }
+
+    /// Template with `{prefix}` resolved.
+    pub fn as_str(&self) -> &str {
+        return &self.template;
+    }
+
+    pub fn render(&self, args: &TopicArgs) -> String {
+        return self
+            .template
+            .replace("{address}", args.address)
+            .replace("{alias}", args.alias)
+            .replace("{adapter}", args.adapter);
+    }
+
+    /// Topic filter matching every device, for templates from `parse_subscription`.
+    pub fn subscription_filter(&self) -> String {
+        return self
+            .template
+            .split('/')
+            .map(|level| match level {
+                "{address}" | "{alias}" => "+",
+                level => level,
+            })
+            .collect::<Vec<&str>>()
+            .join("/");
+    }
+
+    /// Matches topic against template from `parse_subscription`. Returns the name of the
+    /// placeholder and its value in the topic.
+    pub fn match_topic<'a>(&self, topic: &'a str) -> Option<(&'static str, &'a str)> {
+        let template_levels: Vec<&str> = self.template.split('/').collect();
+        let topic_levels: Vec<&str> = topic.split('/').collect();
+        if template_levels.len() != topic_levels.len() {
+            return None;
+        }
+
+        let mut device: Option<(&'static str, &'a str)> = None;
+        for (template_level, topic_level) in template_levels.iter().zip(topic_levels) {
+            match *template_level {
+                "{address}" => device = Some(("address", topic_level)),
+                "{alias}" => device = Some(("alias", topic_level)),
+                template_level => {
+                    if template_level != topic_level {
+                        return None;
+                    }
+                }
+            }
+        }
+        return device;
+    }
+}